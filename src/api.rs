@@ -3,47 +3,183 @@ use std::fmt;
 use std::time;
 use std::io::{Read, Write};
 use std::cell::{RefMut, RefCell};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
 
 use monitorid::MonitorId;
+use utils::{parse_http_date, set_clock_skew, to_timestamp};
 
 use curl;
+use url::Url;
+use flate2::Compression;
+use flate2::write::GzEncoder;
 use serde::{Serialize, Deserialize};
 use serde_json;
+use serde_json::Value;
 
 
-#[derive(Debug, Deserialize)]
-struct ErrorInfo {
-    detail: Option<String>,
-    error: Option<String>,
+/// JSON bodies of at least this many bytes are sent compressed.
+const GZIP_THRESHOLD: usize = 4096;
+
+/// Set once a server answered a compressed body with 415.
+static GZIP_REJECTED: AtomicBool = ATOMIC_BOOL_INIT;
+
+
+#[derive(Serialize)]
+pub struct RunContext {
+    pub hostname: Option<String>,
+    pub fqdn: Option<String>,
+    pub machine_id: Option<String>,
+    pub container_id: Option<String>,
+    pub os: Option<String>,
+    pub kernel: Option<String>,
+    pub user: Option<String>,
+    pub uid: u32,
+    pub cwd: Option<String>,
+    pub cronwell_version: String,
+    pub env: BTreeMap<String, String>,
 }
 
 #[derive(Serialize)]
 pub struct RunStart {
+    pub run_id: Option<String>,
+    pub sequence: u32,
+    pub job: Option<String>,
     pub timestamp: Option<f64>,
     pub command: Option<String>,
     pub args: Option<Vec<String>>,
     pub from_cron: Option<bool>,
+    pub trigger: Option<String>,
+    pub context: Option<RunContext>,
 }
 
 #[derive(Serialize)]
 pub struct RunFailure {
+    pub run_id: Option<String>,
+    pub sequence: u32,
     pub status: i32,
     pub timestamp: f64,
     pub output: Option<String>,
+    pub progress: Option<f64>,
+    pub tags: Option<BTreeMap<String, String>>,
+    pub reported_status: Option<String>,
+    pub event_id: Option<String>,
+    pub clock_skew: Option<f64>,
 }
 
 #[derive(Serialize)]
 pub struct RunComplete {
+    pub run_id: Option<String>,
+    pub sequence: u32,
     pub timestamp: f64,
+    pub progress: Option<f64>,
+    pub tags: Option<BTreeMap<String, String>>,
+    pub reported_status: Option<String>,
+    pub level: Option<String>,
+    pub recovered: Option<bool>,
+    pub consecutive_failures: Option<u32>,
+    pub clock_skew: Option<f64>,
+    /// The output tail for reporters that send it as the body, like
+    /// Healthchecks.  It is not part of the JSON check-in.
+    #[serde(skip_serializing)]
+    pub output: Option<String>,
 }
 
-#[derive(Deserialize)]
-pub struct MonitorStatus {
+#[derive(Serialize)]
+pub struct RunHeartbeat {
+    pub run_id: Option<String>,
+    pub sequence: u32,
+    pub timestamp: f64,
+    pub progress: Option<f64>,
+    pub tags: Option<BTreeMap<String, String>>,
+    pub clock_skew: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RunStep {
+    pub run_id: String,
+    pub step_id: String,
+    pub command: String,
+    pub args: Vec<String>,
     pub status: i32,
+    pub timestamp: f64,
+    pub duration: f64,
+    pub output: Option<String>,
+}
+
+/// A check-in the server can deduplicate.
+///
+/// The run ID and the sequence number of the check-in within the run
+/// (0 for the start) identify it, so a retried or replayed check-in
+/// carries the same key.
+pub trait Idempotent {
+    fn idempotency_key(&self) -> Option<String>;
+}
+
+macro_rules! impl_idempotent {
+    ($($ty:ty),*) => {
+        $(
+            impl Idempotent for $ty {
+                fn idempotency_key(&self) -> Option<String> {
+                    self.run_id.as_ref().map(|x| format!("{}:{}", x, self.sequence))
+                }
+            }
+        )*
+    }
+}
+
+impl_idempotent!(RunStart, RunFailure, RunComplete, RunHeartbeat);
+
+impl Idempotent for RunStep {
+    fn idempotency_key(&self) -> Option<String> {
+        Some(format!("{}:step", self.step_id))
+    }
+}
+
+/// A file uploaded along with a check-in or event.
+pub struct Attachment {
+    pub filename: String,
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MonitorLimits {
+    /// The longest a run may take in seconds.
+    pub max_runtime: Option<u64>,
+}
+
+/// The reply to a check-in.  Everything is optional so older servers
+/// work and fields we do not know about are ignored.
+#[derive(Deserialize, Debug)]
+pub struct MonitorStatus {
+    pub status: Option<i32>,
+    /// The ID the server assigned to the check-in.
+    pub check_in_id: Option<String>,
+    /// One of `active`, `paused` or `disabled`.
+    pub state: Option<String>,
+    pub limits: Option<MonitorLimits>,
+    /// The time on the server as UNIX timestamp.
+    pub server_time: Option<f64>,
+    /// Problems with the check-in that did not make it fail.
+    pub warnings: Option<Vec<String>>,
+}
+
+#[derive(Serialize)]
+pub struct MonitorConfig {
+    pub name: String,
+    pub schedule_type: String,
+    pub schedule: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct MonitorInfo {
+    pub slug: String,
+    pub url: Option<String>,
 }
 
 pub struct Api<'a> {
-    monitor_id: &'a MonitorId,
+    monitor_id: Option<&'a MonitorId>,
     shared_handle: RefCell<curl::easy::Easy>,
 }
 
@@ -51,9 +187,19 @@ pub struct Api<'a> {
 pub enum Method {
     Get,
     Post,
+    Put,
 }
 
+#[derive(Debug)]
 pub enum Error {
+    /// The token was rejected (401 or 403).
+    Auth(u32, String),
+    /// The monitor does not exist (404).
+    NotFound(String),
+    /// Too many requests (429), with the seconds to wait if known.
+    RateLimited(Option<u64>),
+    /// The request was rejected, with the errors per field.
+    Validation(String, BTreeMap<String, Vec<String>>),
     Http(u32, String),
     Curl(curl::Error),
     Io(io::Error),
@@ -64,57 +210,131 @@ pub type ApiResult<T> = Result<T, Error>;
 
 pub struct ApiRequest<'a> {
     handle: RefMut<'a, curl::easy::Easy>,
-    headers: curl::easy::List,
+    headers: Vec<String>,
     body: Option<Vec<u8>>,
+    gzip_body: Option<Vec<u8>>,
 }
 
 #[derive(Clone, Debug)]
 pub struct ApiResponse {
     status: u32,
+    headers: Vec<String>,
     body: Vec<u8>,
 }
 
+impl MonitorStatus {
+    /// Returns `true` if the monitor should not run jobs right now.
+    pub fn is_paused(&self) -> bool {
+        match self.state.as_ref().map(|x| &x[..]) {
+            Some("paused") | Some("disabled") => true,
+            _ => false,
+        }
+    }
+
+    pub fn max_runtime(&self) -> Option<time::Duration> {
+        self.limits.as_ref()
+            .and_then(|x| x.max_runtime)
+            .map(time::Duration::from_secs)
+    }
+}
+
 impl<'a> ApiRequest<'a> {
 
     fn new(mut handle: RefMut<'a, curl::easy::Easy>,
            method: Method, url: &str)
         -> ApiResult<ApiRequest<'a>>
     {
-        let mut headers = curl::easy::List::new();
-        headers.append("Expect:").ok();
-        headers.append(&format!("User-Agent: sentry-cronwell")).ok();
+        let headers = vec![
+            "Expect:".to_string(),
+            format!("User-Agent: sentry-cronwell"),
+        ];
 
         match method {
             Method::Get => handle.get(true)?,
             Method::Post => handle.custom_request("POST")?,
+            Method::Put => handle.custom_request("PUT")?,
         }
 
         handle.url(&url)?;
+        // an empty string enables every encoding curl can decode
+        handle.accept_encoding("")?;
 
         Ok(ApiRequest {
             handle: handle,
             headers: headers,
             body: None,
+            gzip_body: None,
         })
     }
 
+    /// Sets a JSON body.  Large bodies are sent gzip compressed unless
+    /// the server rejected compressed bodies before.
     pub fn with_json_body<S: Serialize>(mut self, body: &S) -> ApiResult<ApiRequest<'a>> {
         let mut body_bytes : Vec<u8> = vec![];
         serde_json::to_writer(&mut body_bytes, &body)?;
+        if body_bytes.len() >= GZIP_THRESHOLD && !GZIP_REJECTED.load(Ordering::Relaxed) {
+            let mut encoder = GzEncoder::new(vec![], Compression::Default);
+            encoder.write_all(&body_bytes)?;
+            self.gzip_body = Some(encoder.finish()?);
+        }
         self.body = Some(body_bytes);
-        self.headers.append("Content-Type: application/json")?;
+        self.headers.push("Content-Type: application/json".to_string());
         Ok(self)
     }
 
-    pub fn send(mut self) -> ApiResult<ApiResponse> {
+    pub fn with_body(mut self, body: Vec<u8>, content_type: &str)
+        -> ApiResult<ApiRequest<'a>>
+    {
+        self.body = Some(body);
+        self.headers.push(format!("Content-Type: {}", content_type));
+        Ok(self)
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> ApiResult<ApiRequest<'a>> {
+        self.headers.push(format!("{}: {}", name, value));
+        Ok(self)
+    }
+
+    fn perform(&mut self, body: Option<Vec<u8>>, extra_header: Option<&str>)
+        -> ApiResult<ApiResponse>
+    {
+        let mut headers = curl::easy::List::new();
+        for header in self.headers.iter().map(|x| &x[..]).chain(extra_header) {
+            headers.append(header)?;
+        }
+        self.handle.http_headers(headers)?;
         let mut out = vec![];
-        self.handle.http_headers(self.headers)?;
-        let (status, _) = send_req(&mut self.handle, &mut out, self.body)?;
+        let (status, headers) = send_req(&mut self.handle, &mut out, body)?;
         Ok(ApiResponse {
             status: status,
+            headers: headers,
             body: out,
         })
     }
+
+    pub fn send(mut self) -> ApiResult<ApiResponse> {
+        if let Some(gzip_body) = self.gzip_body.take() {
+            let resp = self.perform(Some(gzip_body), Some("Content-Encoding: gzip"))?;
+            if resp.status() != 415 {
+                return Ok(resp);
+            }
+            // the server does not take compressed bodies, stop trying
+            GZIP_REJECTED.store(true, Ordering::Relaxed);
+        }
+        let body = self.body.take();
+        self.perform(body, None)
+    }
+}
+
+
+/// Checks that the URL of an endpoint that is not a monitor, like a
+/// webhook or a Pushgateway, can be requested.
+pub fn check_url(url: &str) -> Result<(), String> {
+    match Url::parse(url) {
+        Ok(ref parsed) if parsed.scheme() == "http" || parsed.scheme() == "https" => Ok(()),
+        Ok(parsed) => Err(format!("Unsupported URL scheme {} in {}", parsed.scheme(), url)),
+        Err(err) => Err(format!("Invalid URL {}: {}", url, err)),
+    }
 }
 
 
@@ -122,33 +342,98 @@ impl<'a> Api<'a> {
 
     pub fn new(monitor_id: &'a MonitorId) -> Api<'a> {
         Api {
-            monitor_id: monitor_id,
+            monitor_id: Some(monitor_id),
+            shared_handle: RefCell::new(curl::easy::Easy::new()),
+        }
+    }
+
+    /// Creates a client for endpoints that are not a monitor.  Requests
+    /// have to be made with the full URL.
+    pub fn plain() -> Api<'a> {
+        Api {
+            monitor_id: None,
             shared_handle: RefCell::new(curl::easy::Easy::new()),
         }
     }
 
+    fn api_url(&self) -> &Url {
+        self.monitor_id.expect("monitor request without a monitor").api_url()
+    }
+
     pub fn request(&'a self, method: Method, url: &str) -> ApiResult<ApiRequest<'a>> {
         let mut handle = self.shared_handle.borrow_mut();
         ApiRequest::new(handle, method, url)
     }
 
-    pub fn post<S: Serialize>(&self, path: &str, body: &S) -> ApiResult<ApiResponse> {
-        self.request(Method::Post, path)?.with_json_body(body)?.send()
+    pub fn put<S: Serialize>(&self, path: &str, body: &S) -> ApiResult<ApiResponse> {
+        self.request(Method::Put, path)?.with_json_body(body)?.send()
+    }
+
+    /// Creates or updates a monitor below the API URL we were created with.
+    pub fn upsert_monitor(&self, slug: &str, config: &MonitorConfig)
+        -> ApiResult<MonitorInfo>
+    {
+        self.put(&format!("{}monitors/{}/", self.api_url(), slug), config)?.convert()
+    }
+
+    /// Posts a check-in and measures the clock skew against the server
+    /// from its reply.
+    fn check_in<S: Serialize + Idempotent>(&self, endpoint: &str, body: &S)
+        -> ApiResult<MonitorStatus>
+    {
+        let url = format!("{}{}/", self.api_url(), endpoint);
+        let mut req = self.request(Method::Post, &url)?;
+        if let Some(key) = body.idempotency_key() {
+            req = req.with_header("Idempotency-Key", &key)?;
+        }
+        let resp = req.with_json_body(body)?.send()?;
+        let received = to_timestamp(time::SystemTime::now());
+        let date = resp.get_header("Date").and_then(parse_http_date);
+        let status: MonitorStatus = resp.convert()?;
+        if let Some(server_time) = status.server_time.or(date) {
+            set_clock_skew(server_time - received);
+        }
+        Ok(status)
     }
 
     pub fn send_start(&self, res: &RunStart) -> ApiResult<MonitorStatus>
     {
-        self.post(&format!("{}start/", self.monitor_id.api_url()), res)?.convert()
+        self.check_in("start", res)
     }
 
     pub fn send_failure(&self, res: &RunFailure) -> ApiResult<MonitorStatus>
     {
-        self.post(&format!("{}fail/", self.monitor_id.api_url()), res)?.convert()
+        self.check_in("fail", res)
     }
 
     pub fn send_complete(&self, res: &RunComplete) -> ApiResult<MonitorStatus>
     {
-        self.post(&format!("{}complete/", self.monitor_id.api_url()), res)?.convert()
+        self.check_in("complete", res)
+    }
+
+    pub fn send_heartbeat(&self, res: &RunHeartbeat) -> ApiResult<MonitorStatus>
+    {
+        self.check_in("heartbeat", res)
+    }
+
+    pub fn send_step(&self, res: &RunStep) -> ApiResult<MonitorStatus>
+    {
+        self.check_in("step", res)
+    }
+
+    /// Uploads a file for a run, e.g. the complete log of a failed run.
+    pub fn send_attachment(&self, run_id: &str, attachment: &Attachment)
+        -> ApiResult<MonitorStatus>
+    {
+        let url = format!("{}attachment/?run_id={}", self.api_url(), run_id);
+        self.request(Method::Post, &url)?
+            // a run has at most one attachment
+            .with_header("Idempotency-Key", &format!("{}:attachment", run_id))?
+            .with_header("Content-Disposition",
+                         &format!("attachment; filename=\"{}\"", attachment.filename))?
+            .with_body(attachment.data.clone(), &attachment.content_type)?
+            .send()?
+            .convert()
     }
 }
 
@@ -209,16 +494,61 @@ impl ApiResponse {
         !self.failed()
     }
 
+    /// Returns the value of the last header with this name.
+    pub fn get_header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().rev().filter_map(|line| {
+            let idx = match line.find(':') {
+                Some(idx) => idx,
+                None => return None,
+            };
+            if line[..idx].trim().to_lowercase() == name.to_lowercase() {
+                Some(line[idx + 1..].trim())
+            } else {
+                None
+            }
+        }).next()
+    }
+
+    /// Picks the message and the errors per field out of an error body.
+    fn error_details(&self) -> (Option<String>, BTreeMap<String, Vec<String>>) {
+        let mut detail = None;
+        let mut fields = BTreeMap::new();
+        if let Ok(Value::Object(items)) = self.deserialize::<Value>() {
+            for (key, value) in items {
+                match value {
+                    Value::String(msg) => {
+                        if key == "detail" || key == "error" {
+                            detail = detail.or(Some(msg));
+                        } else {
+                            fields.insert(key, vec![msg]);
+                        }
+                    }
+                    Value::Array(msgs) => {
+                        fields.insert(key, msgs.iter()
+                            .filter_map(|x| x.as_string().map(|x| x.to_string()))
+                            .collect());
+                    }
+                    _ => {}
+                }
+            }
+        }
+        (detail, fields)
+    }
+
     pub fn to_result(self) -> ApiResult<ApiResponse> {
         if self.ok() {
             return Ok(self);
         }
-        if let Ok(err) = self.deserialize::<ErrorInfo>() {
-            if let Some(detail) = err.detail.or(err.error) {
-                fail!(Error::Http(self.status(), detail));
-            }
-        }
-        fail!(Error::Http(self.status(), "generic error".into()));
+        let (detail, fields) = self.error_details();
+        let detail = detail.unwrap_or_else(|| "generic error".into());
+        fail!(match self.status() {
+            401 | 403 => Error::Auth(self.status(), detail),
+            404 => Error::NotFound(detail),
+            429 => Error::RateLimited(self.get_header("Retry-After")
+                .and_then(|x| x.parse().ok())),
+            400 | 422 => Error::Validation(detail, fields),
+            status => Error::Http(status, detail),
+        });
     }
 
     pub fn deserialize<T: Deserialize>(&self) -> ApiResult<T> {
@@ -236,15 +566,55 @@ impl From<curl::Error> for Error {
     }
 }
 
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
 impl From<serde_json::Error> for Error {
     fn from(err: serde_json::Error) -> Error {
         Error::Json(err)
     }
 }
 
+impl Error {
+    /// The exit code for the CLI, following `sysexits.h` where one fits.
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            Error::Auth(..) => 77,
+            Error::NotFound(..) => 78,
+            Error::RateLimited(..) => 75,
+            Error::Validation(..) => 65,
+            _ => 1,
+        }
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
+            Error::Auth(status, ref msg) => {
+                write!(f, "authentication failed: {} ({}); check that the monitor \
+                           token is correct and has not been revoked", msg, status)
+            }
+            Error::NotFound(ref msg) => {
+                write!(f, "monitor not found: {}; check the monitor token or \
+                           create the monitor first", msg)
+            }
+            Error::RateLimited(Some(secs)) => {
+                write!(f, "rate limited by the server, retry in {} seconds", secs)
+            }
+            Error::RateLimited(None) => {
+                write!(f, "rate limited by the server, retry later")
+            }
+            Error::Validation(ref msg, ref fields) => {
+                write!(f, "request rejected: {}", msg)?;
+                for (field, errors) in fields {
+                    write!(f, "\n  {}: {}", field, errors.join(", "))?;
+                }
+                Ok(())
+            }
             Error::Http(status, ref msg) => write!(f, "http error: {} ({})",
                                                    msg, status),
             Error::Curl(ref err) => write!(f, "http error: {}", err),
@@ -253,3 +623,67 @@ impl fmt::Display for Error {
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use std::iter;
+
+    use monitorid::MonitorId;
+    use testutils::serve;
+    use super::{Api, Attachment, RunComplete, check_url};
+
+    #[test]
+    fn test_check_url() {
+        assert!(check_url("http://localhost:9091").is_ok());
+        assert_eq!(check_url("localhost:9091").unwrap_err(),
+                   "Unsupported URL scheme localhost in localhost:9091");
+        assert!(check_url("not a url").is_err());
+    }
+
+    #[test]
+    fn test_attachment_idempotency_key() {
+        let (url, requests) = serve(vec![(200, "{}")]);
+        let id: MonitorId = format!("{}/api/0/monitors/backup/", url).parse().unwrap();
+        Api::new(&id).send_attachment("1234", &Attachment {
+            filename: "output.log.gz".into(),
+            content_type: "application/gzip".into(),
+            data: vec![1, 2, 3],
+        }).unwrap();
+
+        let req = requests.recv().unwrap();
+        assert_eq!(req.path, "/api/0/monitors/backup/attachment/?run_id=1234");
+        assert_eq!(req.header("Idempotency-Key"), Some("1234:attachment"));
+    }
+
+    #[test]
+    fn test_idempotency_key_survives_gzip_fallback() {
+        // this is the only test that gets a 415, so compression was not
+        // turned off for the process yet
+        let (url, requests) = serve(vec![(415, "{}"), (200, "{}")]);
+        let id: MonitorId = format!("{}/api/0/monitors/backup/", url).parse().unwrap();
+        let complete = RunComplete {
+            run_id: Some("1234".into()),
+            sequence: 2,
+            timestamp: 0.0,
+            progress: None,
+            tags: Some(iter::once(("log".to_string(),
+                                   iter::repeat("x").take(8192).collect()))
+                       .collect()),
+            reported_status: None,
+            level: None,
+            recovered: None,
+            consecutive_failures: None,
+            clock_skew: None,
+            output: None,
+        };
+        Api::new(&id).send_complete(&complete).unwrap();
+
+        let compressed = requests.recv().unwrap();
+        let plain = requests.recv().unwrap();
+        assert_eq!(compressed.header("Content-Encoding"), Some("gzip"));
+        assert_eq!(plain.header("Content-Encoding"), None);
+        assert_eq!(compressed.header("Idempotency-Key"), Some("1234:2"));
+        assert_eq!(plain.header("Idempotency-Key"), Some("1234:2"));
+    }
+}
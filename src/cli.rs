@@ -1,14 +1,154 @@
 use std::env;
 use std::mem;
+use std::iter;
 use std::process;
+use std::thread;
 use std::time;
+use std::fs;
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
 use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::sync::mpsc::channel;
 
+use api::{Api, MonitorConfig, RunHeartbeat, RunStep};
+use context::{collect_context, get_hostname};
+use crontab::{Crontab, LintLevel, line_diff, wrap_command};
+use control::{ControlChannel, ControlMessage, ControlState, send_step};
 use error::Error;
+use event::{Dsn, Event, get_dsn};
+use history::{History, RunRecord, get_children_rusage, get_state_dir};
+use hooks::{HookContext, HookResult, run_hook};
+use metrics::{JobMetrics, send_statsd};
 use monitorid::MonitorId;
-use processtools::{ProcessIterator, LineBuffer, get_unix_exit_status};
+use otel::{export_span, get_otlp_endpoint};
+use processtools::{Chunk, ProcessIterator, LineBuffer, RuntimeLimit,
+                   get_unix_exit_status, set_new_process_group};
+use reporter::{Reporter, SentryReporter, WebhookReporter, HealthchecksReporter,
+               FileReporter, NullReporter, MultiReporter};
+use run::{RunInfo, ParentRun, CompleteInfo, is_nested_run};
+use spool::{LogSpool, DEFAULT_LOG_MAX_SIZE, DEFAULT_LOG_RETENTION_DAYS,
+            cleanup_logs, get_spool_dir, load_attachment};
+use systemd::{Notifier, UnitSpec, DEFAULT_ENVIRONMENT_DIR};
+use utils::{clock_skew, format_timestamp, parse_since, set_clock_correction, to_seconds,
+            to_timestamp};
 
-use clap::{App, AppSettings, Arg};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use libc;
+use regex::Regex;
+use serde_json;
+
+
+/// The minimum time between two heartbeats triggered by the process.
+const HEARTBEAT_INTERVAL_SECS: u64 = 10;
+
+/// How many lines of output are kept in the local run history.
+const HISTORY_OUTPUT_LINES: usize = 20;
+
+/// The exit code used when a run is skipped because the monitor is
+/// paused (`EX_TEMPFAIL`).
+const PAUSED_EXIT_CODE: i32 = 75;
+
+
+pub struct RunOptions {
+    pub quiet: bool,
+    pub fail_patterns: Vec<Regex>,
+    pub fail_on_stderr: bool,
+    pub env_capture: Vec<String>,
+    pub job: Option<String>,
+    pub history: History,
+    pub alert_after: u32,
+    pub on_success: Option<String>,
+    pub on_failure: Option<String>,
+    pub hook_timeout: time::Duration,
+    pub respect_pause: bool,
+    pub kill_timeout: time::Duration,
+    pub max_clock_skew: f64,
+    pub correct_clock: bool,
+    pub prom_textfile: Option<String>,
+    pub pushgateway: Option<String>,
+    pub statsd: Option<String>,
+    pub otlp_endpoint: Option<String>,
+    pub dsn: Option<Dsn>,
+    pub spool_dir: PathBuf,
+    pub log_max_size: u64,
+    pub log_retention_days: u64,
+}
+
+/// A check-in sent in the background while the process runs.
+enum BackgroundReport {
+    Heartbeat(RunHeartbeat),
+    Step(RunStep),
+}
+
+/// The command to execute and how it is reported to the monitor.
+pub struct JobCommand {
+    program: String,
+    args: Vec<String>,
+    report_command: String,
+    report_args: Vec<String>,
+}
+
+
+impl JobCommand {
+    pub fn new(program: &str, args: &[&str]) -> JobCommand {
+        let args: Vec<String> = args.iter().map(|x| x.to_string()).collect();
+        JobCommand {
+            program: program.to_string(),
+            args: args.clone(),
+            report_command: program.to_string(),
+            report_args: args,
+        }
+    }
+
+    /// Runs a command string through a shell.  The original string is
+    /// reported rather than the shell.  With `pipefail` a failure anywhere
+    /// in a pipeline fails the job, otherwise only the last command counts
+    /// like it does for plain cron.
+    pub fn shell(shell: &str, cmd: &str, pipefail: bool) -> JobCommand {
+        let mut args: Vec<String> = vec![];
+        if pipefail {
+            args.push("-o".into());
+            args.push("pipefail".into());
+        }
+        args.push("-c".into());
+        args.push(cmd.to_string());
+        JobCommand {
+            program: shell.to_string(),
+            args: args,
+            report_command: cmd.to_string(),
+            report_args: vec![],
+        }
+    }
+
+    /// Runs a script through its interpreter as done for a shebang line.
+    /// The script is reported as the command.
+    pub fn interpreter(interpreter: &[String], script: &str,
+                       args: &[String]) -> JobCommand {
+        let mut full_args: Vec<String> = interpreter[1..].to_vec();
+        full_args.push(script.to_string());
+        full_args.extend(args.iter().cloned());
+        JobCommand {
+            program: interpreter[0].clone(),
+            args: full_args,
+            report_command: script.to_string(),
+            report_args: args.to_vec(),
+        }
+    }
+
+    pub fn report_command(&self) -> &str {
+        &self.report_command
+    }
+
+    pub fn report_args(&self) -> Vec<&str> {
+        self.report_args.iter().map(|x| &x[..]).collect()
+    }
+}
 
 
 pub fn make_app<'a, 'b: 'a>() -> App<'a, 'b> {
@@ -21,6 +161,11 @@ pub fn make_app<'a, 'b: 'a>() -> App<'a, 'b> {
              .long("monitor")
              .short("m")
              .help("The monitor identifier"))
+        .arg(Arg::with_name("monitor_file")
+             .value_name("PATH")
+             .long("monitor-file")
+             .help("Read the monitor identifier from the CRONWELL_MONITOR \
+                    line of this environment file"))
         .arg(Arg::with_name("info")
              .long("info")
              .help("Print basic information about the monitor quit"))
@@ -33,22 +178,389 @@ pub fn make_app<'a, 'b: 'a>() -> App<'a, 'b> {
         .arg(Arg::with_name("fail")
              .long("fail")
              .help("Report a fail for this monitor"))
+        .arg(Arg::with_name("job")
+             .value_name("NAME")
+             .long("job")
+             .help("The name of the job"))
         .arg(Arg::with_name("quiet")
              .long("quiet")
              .short("q")
              .help("Disable output from the process"))
+        .arg(Arg::with_name("fail_on_output")
+             .value_name("REGEX")
+             .long("fail-on-output")
+             .multiple(true)
+             .number_of_values(1)
+             .help("Report a failure if a line of output matches this pattern"))
+        .arg(Arg::with_name("fail_on_stderr")
+             .long("fail-on-stderr")
+             .help("Report a failure if the process writes to stderr"))
+        .arg(Arg::with_name("env_capture")
+             .value_name("VARS")
+             .long("env-capture")
+             .multiple(true)
+             .use_delimiter(true)
+             .help("Environment variables to include in the start report"))
+        .arg(Arg::with_name("shell_command")
+             .value_name("COMMAND")
+             .short("c")
+             .help("Run a command string through the shell"))
+        .arg(Arg::with_name("shell")
+             .value_name("PATH")
+             .long("shell")
+             .default_value("/bin/bash")
+             .help("The shell to use for -c (needs to support pipefail \
+                    unless --no-pipefail is given)"))
+        .arg(Arg::with_name("no_pipefail")
+             .long("no-pipefail")
+             .help("Only fail -c commands if the last command of a pipeline \
+                    fails, like cron does"))
+        .arg(Arg::with_name("report")
+             .value_name("REPORTER")
+             .long("report")
+             .multiple(true)
+             .number_of_values(1)
+             .help("Where to report to: sentry (default), webhook=URL, \
+                    healthchecks=URL, file=PATH or none"))
+        .arg(Arg::with_name("webhook_template")
+             .value_name("TEMPLATE")
+             .long("webhook-template")
+             .help("The JSON body for webhooks with {{field}} placeholders"))
+        .arg(Arg::with_name("alert_after")
+             .value_name("N")
+             .long("alert-after")
+             .help("Only report a failure after N consecutive failed runs"))
+        .arg(Arg::with_name("on_success")
+             .value_name("CMD")
+             .long("on-success")
+             .help("Run this shell command after the job succeeded"))
+        .arg(Arg::with_name("on_failure")
+             .value_name("CMD")
+             .long("on-failure")
+             .help("Run this shell command after the job failed"))
+        .arg(Arg::with_name("hook_timeout")
+             .value_name("SECONDS")
+             .long("hook-timeout")
+             .default_value("60")
+             .help("Kill hooks that run longer than this"))
+        .arg(Arg::with_name("respect_pause")
+             .long("respect-pause")
+             .help("Skip the job if the monitor is paused or disabled"))
+        .arg(Arg::with_name("kill_timeout")
+             .value_name("SECONDS")
+             .long("kill-timeout")
+             .default_value("10")
+             .help("How long a job that exceeded the max runtime of the \
+                    monitor gets to exit before it is killed"))
+        .arg(Arg::with_name("max_clock_skew")
+             .value_name("SECONDS")
+             .long("max-clock-skew")
+             .default_value("30")
+             .help("Warn if the local clock differs more than this from the server"))
+        .arg(Arg::with_name("correct_clock")
+             .long("correct-clock")
+             .help("Adjust reported timestamps by the clock skew the previous \
+                    run measured against the server"))
+        .arg(Arg::with_name("prom_textfile")
+             .value_name("DIR")
+             .long("prom-textfile")
+             .help("Write Prometheus metrics for the textfile collector into DIR"))
+        .arg(Arg::with_name("pushgateway")
+             .value_name("URL")
+             .long("pushgateway")
+             .help("Push Prometheus metrics to this Pushgateway"))
+        .arg(Arg::with_name("statsd")
+             .value_name("HOST:PORT")
+             .long("statsd")
+             .help("Send run metrics to this StatsD/DogStatsD agent"))
+        .arg(Arg::with_name("otlp")
+             .long("otlp")
+             .help("Export each run as an OpenTelemetry span"))
+        .arg(Arg::with_name("otlp_endpoint")
+             .value_name("URL")
+             .long("otlp-endpoint")
+             .help("The OTLP/HTTP traces endpoint (implies --otlp)"))
+        .arg(Arg::with_name("dsn")
+             .value_name("DSN")
+             .long("dsn")
+             .help("Also send an error event to this Sentry DSN when the job \
+                    fails (defaults to SENTRY_DSN)"))
+        .arg(Arg::with_name("spool_dir")
+             .value_name("PATH")
+             .long("spool-dir")
+             .help("Where the complete output of runs is kept \
+                    (defaults to logs/ in the state directory)"))
+        .arg(Arg::with_name("log_max_size")
+             .value_name("BYTES")
+             .long("log-max-size")
+             .help("Stop spooling output after this many bytes"))
+        .arg(Arg::with_name("log_retention_days")
+             .value_name("DAYS")
+             .long("log-retention-days")
+             .help("How many days spooled logs are kept (0 removes those of earlier runs)"))
+        .arg(Arg::with_name("state_dir")
+             .value_name("PATH")
+             .long("state-dir")
+             .help("Where to keep local state (defaults to /var/lib/cronwell)"))
+        .subcommand(SubCommand::with_name("systemd")
+            .about("systemd integration")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("generate")
+                .about("Generate a service and timer unit for a job")
+                .setting(AppSettings::TrailingVarArg)
+                .arg(Arg::with_name("job")
+                     .value_name("NAME")
+                     .long("job")
+                     .required(true)
+                     .help("The name of the job"))
+                .arg(Arg::with_name("schedule")
+                     .value_name("CALENDAR")
+                     .long("schedule")
+                     .required(true)
+                     .help("The schedule in systemd calendar syntax (OnCalendar=)"))
+                .arg(Arg::with_name("watchdog")
+                     .value_name("SECONDS")
+                     .long("watchdog")
+                     .help("Enable the systemd watchdog for the service"))
+                .arg(Arg::with_name("output_dir")
+                     .value_name("PATH")
+                     .long("output-dir")
+                     .help("Write the units into this directory instead of stdout"))
+                .arg(Arg::with_name("env_file")
+                     .value_name("PATH")
+                     .long("env-file")
+                     .help("Where to write the monitor token for the service \
+                            (defaults to /etc/cronwell/<unit>.env)"))
+                .arg(Arg::with_name("command")
+                     .value_name("COMMAND")
+                     .multiple(true)
+                     .required(true)
+                     .help("The command to run"))))
+        .subcommand(SubCommand::with_name("crontab")
+            .about("Work with existing crontabs")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("import")
+                .about("Create monitors for a crontab and wrap its jobs in cronwell")
+                .arg(Arg::with_name("path")
+                     .value_name("FILE")
+                     .index(1)
+                     .help("The crontab to import (defaults to stdin)"))
+                .arg(Arg::with_name("system")
+                     .long("system")
+                     .help("The crontab has a user field (like /etc/crontab)"))
+                .arg(Arg::with_name("diff")
+                     .long("diff")
+                     .help("Only show the changes that would be made"))
+                .arg(Arg::with_name("api_url")
+                     .value_name("URL")
+                     .long("api-url")
+                     .help("The API URL to create the monitors below"))
+                .arg(Arg::with_name("env_dir")
+                     .value_name("DIR")
+                     .long("env-dir")
+                     .help("Where to write the files with the monitor tokens \
+                            (defaults to /etc/cronwell for root and \
+                            ~/.config/cronwell otherwise)"))
+                .arg(Arg::with_name("output")
+                     .value_name("FILE")
+                     .long("output")
+                     .short("o")
+                     .help("Write the rewritten crontab here instead of stdout")))
+            .subcommand(SubCommand::with_name("lint")
+                .about("Check a crontab for invalid and unmonitored entries")
+                .arg(Arg::with_name("path")
+                     .value_name("FILE")
+                     .index(1)
+                     .help("The crontab to check (defaults to stdin)"))
+                .arg(Arg::with_name("system")
+                     .long("system")
+                     .help("The crontab has a user field (like /etc/crontab)"))))
+        .subcommand(SubCommand::with_name("history")
+            .about("Show the local history of runs")
+            .arg(Arg::with_name("job")
+                 .value_name("NAME")
+                 .long("job")
+                 .help("Only show runs of this job"))
+            .arg(Arg::with_name("since")
+                 .value_name("TIME")
+                 .long("since")
+                 .help("Only show runs started after this (timestamp or age like 2h, 7d)"))
+            .arg(Arg::with_name("format")
+                 .value_name("FORMAT")
+                 .long("format")
+                 .possible_values(&["table", "json", "csv"])
+                 .default_value("table")
+                 .help("The output format")))
 }
 
-fn get_monitor_id(s: Option<&str>) -> Result<MonitorId, Error> {
+/// If the file is a script that names cronwell itself as its interpreter
+/// returns what follows the interpreter on the shebang line.
+fn read_cronwell_shebang(path: &str) -> Option<String> {
+    let mut line = String::new();
+    match File::open(path) {
+        Ok(f) => if BufReader::new(f.take(4096)).read_line(&mut line).is_err() {
+            return None;
+        },
+        Err(_) => return None,
+    }
+    if !line.starts_with("#!") {
+        return None;
+    }
+    let line = line[2..].trim();
+    let (interpreter, rest) = match line.find(char::is_whitespace) {
+        Some(idx) => (&line[..idx], &line[idx..]),
+        None => (line, ""),
+    };
+    match (fs::canonicalize(interpreter),
+           env::current_exe().and_then(|x| fs::canonicalize(x))) {
+        (Ok(ref a), Ok(ref b)) if a == b => Some(rest.trim().to_string()),
+        _ => None,
+    }
+}
+
+/// Returns `true` if the file is a script that names cronwell itself as
+/// its interpreter without another interpreter after `--`.  Running such
+/// a script would have the kernel start us with the same script again.
+fn reexecs_itself(path: &str) -> bool {
+    match read_cronwell_shebang(path) {
+        // whatever follows -- is the interpreter we would hand the script to
+        Some(rest) => rest.split_whitespace().skip_while(|x| *x != "--").nth(1).is_none(),
+        None => false,
+    }
+}
+
+/// Splits the arguments cronwell receives when used as a shebang
+/// interpreter (`#!/usr/bin/cronwell --job backup -- /bin/bash`).
+///
+/// Linux passes everything after the interpreter path as a single
+/// argument, followed by the script path and the script's arguments.  If
+/// the script names cronwell in its shebang line and that line is what we
+/// were given, the first argument is split on whitespace and the part
+/// after `--` is returned as the script's interpreter.  Anything else is
+/// an ordinary command line and left alone.
+fn split_shebang_args(args: Vec<String>)
+    -> Result<(Vec<String>, Option<JobCommand>), Error>
+{
+    let is_shebang = args.len() >= 3 &&
+        args[1].contains(char::is_whitespace) &&
+        read_cronwell_shebang(&args[2]).map(|x| x == args[1].trim()).unwrap_or(false);
+    if !is_shebang {
+        // the kernel puts the script after at most one argument
+        let script = match args.get(1) {
+            Some(arg) if arg.starts_with('-') => args.get(2),
+            arg => arg,
+        };
+        if let Some(script) = script {
+            if reexecs_itself(script) {
+                fail!("{} uses cronwell as its interpreter but does not name \
+                       the script's interpreter after --", script);
+            }
+        }
+        return Ok((args, None));
+    }
+
+    let mut cronwell_args = vec![args[0].clone()];
+    let mut interpreter = vec![];
+    let mut in_interpreter = false;
+    for token in args[1].split_whitespace() {
+        if in_interpreter {
+            interpreter.push(token.to_string());
+        } else if token == "--" {
+            in_interpreter = true;
+        } else {
+            cronwell_args.push(token.to_string());
+        }
+    }
+    if interpreter.is_empty() {
+        fail!("Shebang line is missing the interpreter after --");
+    }
+
+    let job = JobCommand::interpreter(&interpreter[..], &args[2], &args[3..]);
+    Ok((cronwell_args, Some(job)))
+}
+
+/// Reads the monitor token from an environment file as written by
+/// `crontab import` and `systemd generate`.
+fn read_monitor_file(path: &str) -> Result<String, Error> {
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.starts_with("CRONWELL_MONITOR=") {
+            return Ok(line["CRONWELL_MONITOR=".len()..].to_string());
+        }
+    }
+    fail!("No CRONWELL_MONITOR in {}", path);
+}
+
+/// Looks up the monitor token from the arguments, a monitor file or the
+/// environment.
+fn find_monitor_id(s: Option<&str>, file: Option<&str>) -> Result<Option<MonitorId>, Error> {
     if let Some(val) = s {
-        return val.parse();
+        return Ok(Some(val.parse()?));
+    }
+    if let Some(path) = file {
+        return Ok(Some(read_monitor_file(path)?.parse()?));
     }
     if let Ok(val) = env::var("CRONWELL_MONITOR") {
         if !val.is_empty() {
-            return val.parse();
+            return Ok(Some(val.parse()?));
+        }
+    }
+    Ok(None)
+}
+
+fn get_monitor_id(s: Option<&str>, file: Option<&str>) -> Result<MonitorId, Error> {
+    match find_monitor_id(s, file)? {
+        Some(id) => Ok(id),
+        None => fail!("No monitor token provided."),
+    }
+}
+
+fn make_reporter(matches: &ArgMatches, id: Option<&MonitorId>)
+    -> Result<Box<Reporter>, Error>
+{
+    let specs: Vec<&str> = match matches.values_of("report") {
+        Some(values) => values.collect(),
+        None => vec!["sentry"],
+    };
+    let template = matches.value_of("webhook_template").map(|x| x.to_string());
+
+    let mut reporters: Vec<Box<Reporter>> = vec![];
+    for spec in specs {
+        let (kind, value) = match spec.find('=') {
+            Some(idx) => (&spec[..idx], Some(&spec[idx + 1..])),
+            None => (spec, None),
+        };
+        match (kind, value) {
+            ("sentry", None) => {
+                let id = match id {
+                    Some(id) => id.clone(),
+                    None => fail!("No monitor token provided."),
+                };
+                reporters.push(Box::new(SentryReporter::new(id)));
+            }
+            ("webhook", Some(url)) => {
+                reporters.push(Box::new(WebhookReporter::new(url, template.clone())?));
+            }
+            ("healthchecks", Some(url)) | ("hc", Some(url)) => {
+                reporters.push(Box::new(HealthchecksReporter::new(url)?));
+            }
+            ("file", Some(path)) => {
+                reporters.push(Box::new(FileReporter::new(path)));
+            }
+            ("none", None) => {
+                reporters.push(Box::new(NullReporter));
+            }
+            _ => fail!("Invalid reporter '{}'", spec),
         }
     }
-    fail!("No monitor token provided.");
+
+    if reporters.len() == 1 {
+        Ok(reporters.pop().unwrap())
+    } else {
+        Ok(Box::new(MultiReporter::new(reporters)))
+    }
 }
 
 fn print_monitor_info(id: &MonitorId) -> Result<(), Error> {
@@ -58,48 +570,715 @@ fn print_monitor_info(id: &MonitorId) -> Result<(), Error> {
     Ok(())
 }
 
-fn run_command(id: &MonitorId, cmd: &str, args: &[&str],
-               quiet: bool) -> Result<(), Error> {
-    id.report_start(cmd, args).ok();
+fn write_unit(output_dir: Option<&str>, name: &str,
+              contents: &str) -> Result<(), Error> {
+    match output_dir {
+        Some(dir) => {
+            let path = Path::new(dir).join(name);
+            let mut f = File::create(&path)?;
+            f.write_all(contents.as_bytes())?;
+            println!("Wrote {}", path.display());
+        }
+        None => {
+            println!("# {}", name);
+            print!("{}", contents);
+        }
+    }
+    Ok(())
+}
+
+fn execute_systemd_generate(matches: &ArgMatches,
+                            sub_matches: &ArgMatches) -> Result<(), Error> {
+    let watchdog_sec = match sub_matches.value_of("watchdog") {
+        Some(value) => Some(value.parse::<u64>()
+            .map_err(|_| "Invalid watchdog interval")?),
+        None => None,
+    };
+    // names are escaped, but the job also ends up in the description
+    let job = sub_matches.value_of("job").unwrap();
+    if job.is_empty() || job.contains(char::is_control) {
+        fail!("Invalid job name '{}'", job.escape_default());
+    }
+    let cronwell = env::current_exe()?;
+    let spec = UnitSpec {
+        job: job.to_string(),
+        schedule: sub_matches.value_of("schedule").unwrap().to_string(),
+        command: sub_matches.values_of("command").unwrap()
+            .map(|x| x.to_string()).collect(),
+        cronwell: cronwell.to_string_lossy().into_owned(),
+        monitor_token: match matches.value_of("monitor_id") {
+            Some(val) => Some(val.parse::<MonitorId>()?.token()),
+            None => None,
+        },
+        environment_file: sub_matches.value_of("env_file").map(|x| x.to_string()),
+        watchdog_sec: watchdog_sec,
+    };
+
+    // the token never goes to stdout, it is only readable by the owner
+    if let Some(environment) = spec.generate_environment() {
+        let path = PathBuf::from(spec.environment_path());
+        write_private_file(&path, &environment)?;
+        writeln!(&mut io::stderr(), "Wrote {}", path.display()).ok();
+    }
 
-    let mut child = Command::new(cmd)
-        .args(args)
+    let output_dir = sub_matches.value_of("output_dir");
+    write_unit(output_dir, &spec.service_name(), &spec.generate_service())?;
+    if output_dir.is_none() {
+        println!("");
+    }
+    write_unit(output_dir, &spec.timer_name(), &spec.generate_timer())?;
+    Ok(())
+}
+
+/// Writes a file only its owner can read, like one with a monitor token.
+fn write_private_file(path: &Path, contents: &str) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut f = OpenOptions::new().write(true).create(true).truncate(true)
+        .mode(0o600).open(path)?;
+    f.set_permissions(fs::Permissions::from_mode(0o600))?;
+    f.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+/// Hands a file over to a user so that their jobs can read it.
+fn chown_to_user(path: &Path, user: &str) -> Result<(), Error> {
+    let c_user = CString::new(user).map_err(|_| format!("Invalid user name {}", user))?;
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| format!("Invalid path {}", path.display()))?;
+    unsafe {
+        let pw = libc::getpwnam(c_user.as_ptr());
+        if pw.is_null() {
+            fail!("Unknown user {}", user);
+        }
+        if libc::chown(c_path.as_ptr(), (*pw).pw_uid, (*pw).pw_gid) != 0 {
+            return Err(Error::from(io::Error::last_os_error()));
+        }
+    }
+    Ok(())
+}
+
+/// Where crontab import puts the monitor tokens unless told otherwise.
+/// Other users than root cannot write to the system directory.
+fn get_env_dir(value: Option<&str>) -> PathBuf {
+    if let Some(value) = value {
+        return PathBuf::from(value);
+    }
+    if unsafe { libc::geteuid() } != 0 {
+        if let Some(home) = env::home_dir() {
+            return home.join(".config/cronwell");
+        }
+    }
+    PathBuf::from(DEFAULT_ENVIRONMENT_DIR)
+}
+
+fn read_crontab(path: Option<&str>) -> Result<(String, String, bool), Error> {
+    let mut contents = String::new();
+    let name = match path {
+        None | Some("-") => {
+            io::stdin().read_to_string(&mut contents)?;
+            "-".to_string()
+        }
+        Some(path) => {
+            File::open(path)?.read_to_string(&mut contents)?;
+            path.to_string()
+        }
+    };
+    let system = name == "/etc/crontab" || name.starts_with("/etc/cron.d/");
+    Ok((contents, name, system))
+}
+
+fn execute_crontab_import(matches: &ArgMatches) -> Result<(), Error> {
+    let (contents, name, system) = read_crontab(matches.value_of("path"))?;
+    let crontab = Crontab::parse(&contents, system || matches.is_present("system"));
+    let diff_only = matches.is_present("diff");
+
+    let api_base: Option<MonitorId> = match matches.value_of("api_url")
+            .map(|x| x.to_string())
+            .or_else(|| env::var("CRONWELL_API_URL").ok()) {
+        Some(url) => Some(url.parse()?),
+        None if diff_only => None,
+        None => fail!("No API URL provided (--api-url or CRONWELL_API_URL)"),
+    };
+    let cronwell = env::current_exe()?.to_string_lossy().into_owned();
+    // the jobs keep running in the shell cron would have used
+    let shell = crontab.env("SHELL");
+    let env_dir = get_env_dir(matches.value_of("env_dir"));
+
+    let (old_lines, new_lines) = crontab.rewrite_jobs(|job, slug| {
+        let monitor_url = match api_base {
+            Some(ref base) => {
+                let info = Api::new(base).upsert_monitor(slug, &MonitorConfig {
+                    name: slug.to_string(),
+                    schedule_type: "crontab".into(),
+                    schedule: job.crontab_schedule(),
+                })?;
+                info.url.unwrap_or_else(|| format!("{}monitors/{}/", base.api_url(), slug))
+            }
+            None => format!("<monitor:{}>", slug),
+        };
+        let token = match monitor_url.parse::<MonitorId>() {
+            Ok(id) => id.token(),
+            Err(_) => monitor_url,
+        };
+        // crontabs are often world readable and arguments show up in ps,
+        // so the token goes into a file only the job's user can read
+        let path = env_dir.join(format!("crontab-{}.env", slug));
+        if !diff_only {
+            write_private_file(&path, &format!("CRONWELL_MONITOR={}\n", token))?;
+            if let Some(ref user) = job.user {
+                chown_to_user(&path, user)?;
+            }
+            writeln!(&mut io::stderr(), "Wrote {}", path.display()).ok();
+        }
+        let path = path.to_string_lossy();
+        Ok(job.rewrite(|cmd| wrap_command(&cronwell, &path, slug, shell, cmd)))
+    })?;
+
+    if diff_only {
+        print!("{}", line_diff(&name, &old_lines, &new_lines));
+        return Ok(());
+    }
+
+    let mut output = new_lines.join("\n");
+    output.push('\n');
+    match matches.value_of("output") {
+        Some(path) => File::create(path)?.write_all(output.as_bytes())?,
+        None => print!("{}", output),
+    }
+    Ok(())
+}
+
+fn execute_crontab_lint(matches: &ArgMatches) -> Result<(), Error> {
+    let (contents, name, system) = read_crontab(matches.value_of("path"))?;
+    let crontab = Crontab::parse(&contents, system || matches.is_present("system"));
+    let lints = crontab.lint();
+
+    for lint in &lints {
+        let level = match lint.level {
+            LintLevel::Warning => "warning",
+            LintLevel::Error => "error",
+        };
+        if lint.lineno > 0 {
+            println!("{}:{}: {}: {}", name, lint.lineno, level, lint.message);
+        } else {
+            println!("{}: {}: {}", name, level, lint.message);
+        }
+    }
+
+    if !lints.is_empty() {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains(|c: char| c == ',' || c == '"' || c == '\n') {
+        format!("\"{}\"", value.replace("\"", "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn execute_history(matches: &ArgMatches, sub_matches: &ArgMatches)
+    -> Result<(), Error>
+{
+    let history = History::new(&get_state_dir(matches.value_of("state_dir")));
+    let since = match sub_matches.value_of("since") {
+        Some(value) => Some(parse_since(value, to_timestamp(time::SystemTime::now()))
+            .ok_or("Invalid value for --since")?),
+        None => None,
+    };
+    let records = history.query(sub_matches.value_of("job"), since)?;
+
+    match sub_matches.value_of("format").unwrap_or("table") {
+        "json" => {
+            println!("{}", serde_json::to_string_pretty(&records)
+                .map_err(|err| format!("could not serialize history: {}", err))?);
+        }
+        "csv" => {
+            println!("run_id,job,monitor,command,start,end,duration,status,\
+                      failed,user_time,system_time,max_rss_kb");
+            for record in &records {
+                let (utime, stime, maxrss) = match record.rusage {
+                    Some(ref r) => (r.user_time.to_string(), r.system_time.to_string(),
+                                    r.max_rss_kb.to_string()),
+                    None => (String::new(), String::new(), String::new()),
+                };
+                println!("{},{},{},{},{},{},{},{},{},{},{},{}",
+                         record.run_id,
+                         csv_field(record.job.as_ref().map(|x| &x[..]).unwrap_or("")),
+                         csv_field(&record.monitor),
+                         csv_field(&record.command),
+                         record.start, record.end, record.duration,
+                         record.status, record.failed, utime, stime, maxrss);
+            }
+        }
+        _ => {
+            println!("{:<19}  {:<20}  {:<12}  {:>9}  {}",
+                     "START", "JOB", "STATUS", "DURATION", "COMMAND");
+            for record in &records {
+                let status = if record.failed {
+                    format!("failed ({})", record.status)
+                } else {
+                    "ok".to_string()
+                };
+                println!("{:<19}  {:<20}  {:<12}  {:>8.1}s  {}",
+                         format_timestamp(record.start),
+                         record.job.as_ref().map(|x| &x[..]).unwrap_or("-"),
+                         status, record.duration, record.command);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn get_run_options(matches: &ArgMatches) -> Result<RunOptions, Error> {
+    let mut fail_patterns = vec![];
+    if let Some(values) = matches.values_of("fail_on_output") {
+        for value in values {
+            fail_patterns.push(Regex::new(value)?);
+        }
+    }
+    let state_dir = get_state_dir(matches.value_of("state_dir"));
+    Ok(RunOptions {
+        quiet: matches.is_present("quiet"),
+        fail_patterns: fail_patterns,
+        fail_on_stderr: matches.is_present("fail_on_stderr"),
+        env_capture: match matches.values_of("env_capture") {
+            Some(values) => values.map(|x| x.to_string()).collect(),
+            None => vec![],
+        },
+        job: matches.value_of("job").map(|x| x.to_string()),
+        history: History::new(&state_dir),
+        alert_after: match matches.value_of("alert_after") {
+            Some(value) => value.parse().map_err(|_| "Invalid value for --alert-after")?,
+            None => 1,
+        },
+        on_success: matches.value_of("on_success").map(|x| x.to_string()),
+        on_failure: matches.value_of("on_failure").map(|x| x.to_string()),
+        hook_timeout: time::Duration::from_secs(matches.value_of("hook_timeout")
+            .unwrap_or("60").parse().map_err(|_| "Invalid value for --hook-timeout")?),
+        respect_pause: matches.is_present("respect_pause"),
+        kill_timeout: time::Duration::from_secs(matches.value_of("kill_timeout")
+            .unwrap_or("10").parse().map_err(|_| "Invalid value for --kill-timeout")?),
+        correct_clock: matches.is_present("correct_clock"),
+        max_clock_skew: matches.value_of("max_clock_skew")
+            .unwrap_or("30").parse().map_err(|_| "Invalid value for --max-clock-skew")?,
+        prom_textfile: matches.value_of("prom_textfile").map(|x| x.to_string()),
+        pushgateway: matches.value_of("pushgateway").map(|x| x.to_string()),
+        statsd: matches.value_of("statsd").map(|x| x.to_string()),
+        otlp_endpoint: if matches.is_present("otlp") || matches.is_present("otlp_endpoint") {
+            Some(get_otlp_endpoint(matches.value_of("otlp_endpoint")))
+        } else {
+            None
+        },
+        dsn: get_dsn(matches.value_of("dsn"))?,
+        spool_dir: get_spool_dir(matches.value_of("spool_dir"), &state_dir),
+        log_max_size: match matches.value_of("log_max_size") {
+            Some(value) => value.parse().map_err(|_| "Invalid value for --log-max-size")?,
+            None => DEFAULT_LOG_MAX_SIZE,
+        },
+        log_retention_days: match matches.value_of("log_retention_days") {
+            Some(value) => value.parse().map_err(|_| "Invalid value for --log-retention-days")?,
+            None => DEFAULT_LOG_RETENTION_DAYS,
+        },
+    })
+}
+
+/// Records a run in the history and returns how many runs before it
+/// failed in a row together with the number of failures to alert at.
+///
+/// Failures cannot be counted without a working history, so if it
+/// cannot be read or written every failure alerts.
+fn track_failures(history: &History, record: &RunRecord, alert_after: u32) -> (u32, u32) {
+    let rv = history.consecutive_failures(record.key())
+        .and_then(|previous| history.append(record).map(|_| previous));
+    match rv {
+        Ok(previous) => (previous, alert_after),
+        Err(err) => {
+            if alert_after > 1 {
+                writeln!(&mut io::stderr(), "warning: could not use the run history ({}), \
+                                             --alert-after is ignored and every failure \
+                                             is reported", err).ok();
+            }
+            (0, 1)
+        }
+    }
+}
+
+fn run_command(reporter: Box<Reporter>, id: Option<&MonitorId>, job: &JobCommand,
+               opts: RunOptions) -> Result<(), Error> {
+    let cmd = job.report_command();
+    let report_args = job.report_args();
+    let args = &report_args[..];
+    let run = RunInfo::from_env();
+    let reporter = Arc::new(reporter);
+    let started = time::Instant::now();
+    let started_at = time::SystemTime::now();
+
+    // set up before the start is reported so that a failure here does
+    // not leave a check-in behind that never completes
+    let mut control_channel = ControlChannel::new()?;
+
+    // the skew is measured from the replies, so the start would be the
+    // only check-in on the local clock.  Every check-in of this run uses
+    // the skew the previous run measured instead.
+    if opts.correct_clock {
+        if let Some(skew) = opts.history.load_clock_skew() {
+            set_clock_correction(skew);
+        }
+    }
+
+    let monitor_status = if run.is_nested() {
+        None
+    } else {
+        let env_capture: Vec<&str> = opts.env_capture.iter().map(|x| &x[..]).collect();
+        match reporter.report_start(&run.start_payload(
+                opts.job.as_ref().map(|x| &x[..]), cmd, args,
+                collect_context(&env_capture[..]))) {
+            Ok(monitor_status) => monitor_status,
+            Err(err) => {
+                // the job runs anyway, but the user should learn why
+                // the monitor will not see it
+                writeln!(&mut io::stderr(), "warning: could not report start: {}", err).ok();
+                None
+            }
+        }
+    };
+
+    if let Some(skew) = clock_skew() {
+        if skew.abs() > opts.max_clock_skew {
+            writeln!(&mut io::stderr(), "warning: local clock is {:.0}s {} the server, \
+                                         check that NTP is working",
+                     skew.abs(), if skew > 0.0 { "behind" } else { "ahead of" }).ok();
+        }
+        if opts.correct_clock {
+            opts.history.save_clock_skew(skew).ok();
+        }
+    }
+
+    if let Some(ref monitor_status) = monitor_status {
+        if let Some(ref warnings) = monitor_status.warnings {
+            for warning in warnings {
+                writeln!(&mut io::stderr(), "warning: server: {}", warning).ok();
+            }
+        }
+        if opts.respect_pause && monitor_status.is_paused() {
+            writeln!(&mut io::stderr(), "cronwell: monitor is {}, skipping {}",
+                     monitor_status.state.as_ref().map(|x| &x[..]).unwrap_or("paused"),
+                     cmd).ok();
+            // close the check-in that was just opened so the monitor does
+            // not wait for a run that never happens
+            let mut skipped = ControlState::default();
+            skipped.status = Some("skipped".into());
+            if let Err(err) = reporter.report_complete(&run.complete_payload(
+                    iter::empty(), &skipped, &CompleteInfo::default())) {
+                writeln!(&mut io::stderr(), "warning: could not report skip: {}", err).ok();
+            }
+            process::exit(PAUSED_EXIT_CODE);
+        }
+    }
+
+    let mut command = Command::new(&job.program);
+    command.args(&job.args);
+    for (key, value) in run.env_vars(id) {
+        command.env(key, value);
+    }
+    if let Some(fd) = control_channel.child_fd() {
+        command.env("CRONWELL_CONTROL_FD", fd.to_string());
+    }
+    // the token carries the secret of the monitor, the child only gets
+    // its name in CRONWELL_MONITOR_NAME
+    command.env_remove("CRONWELL_MONITOR");
+    // notifications are ours, the child is not the main process
+    command.env_remove("NOTIFY_SOCKET");
+    command.env_remove("WATCHDOG_USEC");
+    command.env_remove("WATCHDOG_PID");
+    // with a runtime limit the job gets a process group of its own so a
+    // timeout ends the whole pipeline.  Without one it stays in ours so
+    // Ctrl-C still reaches it when run interactively.
+    let max_runtime = monitor_status.as_ref().and_then(|x| x.max_runtime());
+    if max_runtime.is_some() {
+        set_new_process_group(&mut command);
+    }
+    let mut child = command
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .spawn()?;
+    control_channel.close_child_end();
+
+    let runtime_limit = max_runtime.map(|x| {
+        RuntimeLimit::spawn(&child, x, opts.kill_timeout)
+    });
+
+    let notifier = Notifier::from_env();
+    let watchdog = notifier.as_ref().and_then(|notifier| {
+        notifier.ready();
+        notifier.status(&format!("Running {}", cmd));
+        notifier.spawn_watchdog()
+    });
 
     let mut buf = LineBuffer::new(200);
+    buf.set_fail_patterns(opts.fail_patterns);
+    buf.set_fail_on_stderr(opts.fail_on_stderr);
+
+    // the outer run already spools the output of nested runs
+    let mut spool = if run.is_nested() {
+        None
+    } else {
+        LogSpool::create(&opts.spool_dir, &run.run_id, opts.log_max_size).ok()
+    };
+
+    let mut control = ControlState::default();
+    let mut last_heartbeat: Option<time::Instant> = None;
+
+    // heartbeats and steps are sent from their own thread so that a slow
+    // server does not hold up the output of the process
+    let (report_tx, report_rx) = channel();
+    let report_thread = {
+        let reporter = reporter.clone();
+        thread::spawn(move || {
+            for report in report_rx {
+                match report {
+                    BackgroundReport::Heartbeat(heartbeat) => {
+                        reporter.report_heartbeat(&heartbeat).ok();
+                    }
+                    BackgroundReport::Step(step) => {
+                        reporter.report_step(&step).ok();
+                    }
+                }
+            }
+        })
+    };
 
     // while there is output, output it
     {
-        let iter = ProcessIterator::new(&mut child);
+        let iter = ProcessIterator::with_control(&mut child, control_channel.into_reader());
         for chunk in iter {
-            if !quiet {
-                chunk.echo().ok();
+            if let Some(ref watchdog) = watchdog {
+                watchdog.progress();
+            }
+            match chunk {
+                Chunk::Control(ControlMessage::Step(step)) => {
+                    // steps of deeper levels are passed up to the outermost run
+                    match run.parent {
+                        Some(ParentRun { control_fd: Some(fd), .. }) => {
+                            send_step(fd, &step).ok();
+                        }
+                        Some(_) => {}
+                        None => { report_tx.send(BackgroundReport::Step(step)).ok(); }
+                    }
+                }
+                Chunk::Control(msg) => {
+                    control.apply(msg);
+                    let due = last_heartbeat.map(|x| {
+                        x.elapsed().as_secs() >= HEARTBEAT_INTERVAL_SECS
+                    }).unwrap_or(true);
+                    if due && !run.is_nested() {
+                        report_tx.send(BackgroundReport::Heartbeat(
+                            run.heartbeat_payload(&control))).ok();
+                        last_heartbeat = Some(time::Instant::now());
+                    }
+                }
+                chunk => {
+                    if !opts.quiet {
+                        chunk.echo().ok();
+                    }
+                    if let Some(ref mut spool) = spool {
+                        spool.write_chunk(&chunk).ok();
+                    }
+                    buf.append_chunk(&chunk);
+                }
             }
-            buf.append_chunk(&chunk);
         }
     }
 
+    if let Some(ref watchdog) = watchdog {
+        watchdog.job_exited();
+    }
+
+    // everything sent during the run goes out before the final check-in
+    drop(report_tx);
+    report_thread.join().ok();
+
     let status = child.wait().ok().and_then(get_unix_exit_status).unwrap_or(255);
+    let timed_out = runtime_limit.map(|x| x.finish()).unwrap_or(false);
+    if timed_out {
+        writeln!(&mut io::stderr(), "cronwell: {} exceeded the max runtime of {}s",
+                 cmd, max_runtime.map(|x| x.as_secs()).unwrap_or(0)).ok();
+        if control.status.is_none() {
+            control.status = Some("timeout".into());
+        }
+    }
+
+    if let Some(ref notifier) = notifier {
+        notifier.status(&format!("{} exited with status {}", cmd, status));
+    }
+
+    let log_path = spool.and_then(|x| x.finish().ok());
+
+    // a clean exit still counts as a failure if the output told us so
+    let failed = status != 0 || timed_out || buf.output_failed() ||
+        control.reported_failure();
+    let duration = started.elapsed();
 
-    if status == 0 {
-        id.report_complete().ok();
+    let monitor = id.map(|x| x.name()).unwrap_or(String::new());
+    let record = RunRecord {
+        run_id: run.run_id.clone(),
+        monitor: monitor.clone(),
+        job: opts.job.clone(),
+        command: cmd.to_string(),
+        start: to_timestamp(started_at),
+        end: to_timestamp(started_at + duration),
+        duration: to_seconds(duration),
+        status: status,
+        failed: failed,
+        rusage: get_children_rusage(),
+        output: buf.tail(HISTORY_OUTPUT_LINES),
+    };
+
+    // failures are counted per job in the local history
+    let (previous_failures, alert_after) = if run.is_nested() {
+        (0, opts.alert_after)
+    } else {
+        track_failures(&opts.history, &record, opts.alert_after)
+    };
+
+    if !run.is_nested() {
+        if opts.prom_textfile.is_some() || opts.pushgateway.is_some() {
+            let metrics = JobMetrics::from_run(&record, &opts.history);
+            if let Some(ref dir) = opts.prom_textfile {
+                if let Err(err) = metrics.write_textfile(Path::new(dir)) {
+                    writeln!(&mut io::stderr(), "warning: could not write metrics: {}", err).ok();
+                }
+            }
+            if let Some(ref url) = opts.pushgateway {
+                if let Err(err) = metrics.push(url) {
+                    writeln!(&mut io::stderr(), "warning: could not push metrics: {}", err).ok();
+                }
+            }
+        }
+        if let Some(ref addr) = opts.statsd {
+            let host = get_hostname();
+            if let Err(err) = send_statsd(&record, addr, host.as_ref().map(|x| &x[..])) {
+                writeln!(&mut io::stderr(), "warning: could not send statsd metrics: {}", err).ok();
+            }
+        }
+    }
+
+    // nested runs export a span too, it becomes a child of the outer run
+    if let Some(ref endpoint) = opts.otlp_endpoint {
+        let host = get_hostname();
+        if let Err(err) = export_span(endpoint, &run, &record, host.as_ref().map(|x| &x[..])) {
+            writeln!(&mut io::stderr(), "warning: could not export span: {}", err).ok();
+        }
+    }
+
+    let hook = if failed { opts.on_failure.as_ref() } else { opts.on_success.as_ref() };
+    let hook_output = if hook.is_some() { buf.tail(::std::usize::MAX) } else { vec![] };
+
+    if let Some(ref parent) = run.parent {
+        if let (Some(fd), Some(step)) = (parent.control_fd, run.step_payload(
+                cmd, args, buf.into_iter(), status, failed, duration)) {
+            send_step(fd, &step).ok();
+        }
+    } else if !failed {
+        reporter.report_complete(&run.complete_payload(buf.into_iter(), &control, &CompleteInfo {
+            recovered: previous_failures > 0,
+            ..Default::default()
+        })).ok();
+    } else if previous_failures + 1 < alert_after {
+        reporter.report_complete(&run.complete_payload(buf.into_iter(), &control, &CompleteInfo {
+            level: Some("warning".into()),
+            consecutive_failures: Some(previous_failures + 1),
+            ..Default::default()
+        })).ok();
     } else {
-        id.report_failure(buf.into_iter(), status).ok();
+        let lines = buf.tail(::std::usize::MAX);
+        let attachment = log_path.as_ref().and_then(|x| load_attachment(x).ok());
+        let mut failure = run.failure_payload(lines.iter().cloned(), status, &control);
+        if let Some(ref dsn) = opts.dsn {
+            let event = Event::from_failure(&run, &record, args, &lines[..],
+                                            &collect_context(&[]));
+            match event.send(dsn, attachment.as_ref()) {
+                Ok(()) => failure.event_id = Some(event.event_id.clone()),
+                Err(err) => {
+                    writeln!(&mut io::stderr(), "warning: could not send error event: {}", err).ok();
+                }
+            }
+        }
+        reporter.report_failure(&failure).ok();
+        // without an event the log goes to the check-in instead
+        if failure.event_id.is_none() {
+            if let Some(ref attachment) = attachment {
+                reporter.report_attachment(&run.run_id, attachment).ok();
+            }
+        }
+    }
+
+    // hooks run once the monitor knows the outcome so that a slow hook
+    // cannot hold up the check-in
+    if let Some(hook) = hook {
+        let ctx = HookContext {
+            run_id: &run.run_id,
+            job: opts.job.as_ref().map(|x| &x[..]),
+            command: cmd,
+            status: status,
+            failed: failed,
+            duration: to_seconds(duration),
+            output: &hook_output[..],
+        };
+        // hooks must never change the outcome of the job itself
+        match run_hook(hook, &ctx, opts.hook_timeout) {
+            Ok(HookResult::Exited(0)) => {}
+            Ok(HookResult::Exited(code)) => {
+                writeln!(&mut io::stderr(), "warning: hook exited with status {}", code).ok();
+            }
+            Ok(HookResult::TimedOut) => {
+                writeln!(&mut io::stderr(), "warning: hook timed out and was killed").ok();
+            }
+            Err(err) => {
+                writeln!(&mut io::stderr(), "warning: could not run hook: {}", err).ok();
+            }
+        }
+    }
+
+    if let Some(ref log_path) = log_path {
+        cleanup_logs(&opts.spool_dir, opts.log_retention_days, log_path, started_at).ok();
     }
 
     process::exit(status);
 }
 
 pub fn execute() -> Result<(), Error> {
-    let args : Vec<String> = env::args().collect();
+    let (args, shebang_job) = split_shebang_args(env::args().collect())?;
 
     let matches = make_app().get_matches_from_safe(args)?;
-    let id = get_monitor_id(matches.value_of("monitor_id"))?;
+
+    if let Some(sub_matches) = matches.subcommand_matches("systemd") {
+        if let Some(gen_matches) = sub_matches.subcommand_matches("generate") {
+            return execute_systemd_generate(&matches, gen_matches);
+        }
+    }
+
+    if let Some(sub_matches) = matches.subcommand_matches("crontab") {
+        if let Some(import_matches) = sub_matches.subcommand_matches("import") {
+            return execute_crontab_import(import_matches);
+        }
+        if let Some(lint_matches) = sub_matches.subcommand_matches("lint") {
+            return execute_crontab_lint(lint_matches);
+        }
+    }
+
+    if let Some(sub_matches) = matches.subcommand_matches("history") {
+        return execute_history(&matches, sub_matches);
+    }
 
     if matches.is_present("info") {
-        print_monitor_info(&id)?;
+        print_monitor_info(&get_monitor_id(matches.value_of("monitor_id"),
+                                           matches.value_of("monitor_file"))?)?;
     } else if matches.is_present("start") {
         //id.report_start()?;
     } else if matches.is_present("complete") {
@@ -107,18 +1286,134 @@ pub fn execute() -> Result<(), Error> {
     } else if matches.is_present("fail") {
         //id.report_status(false)?;
     } else {
-        let quiet = matches.is_present("quiet");
-        match matches.subcommand() {
-            (exe, Some(exe_matches)) => {
-                let args = match exe_matches.values_of("") {
-                    Some(args) => args.collect(),
-                    None => vec![],
-                };
-                run_command(&id, exe, &args[..], quiet)?;
+        let id = find_monitor_id(matches.value_of("monitor_id"),
+                                 matches.value_of("monitor_file"))?;
+        // nested runs report through the outer run
+        let reporter: Box<Reporter> = if is_nested_run() {
+            Box::new(NullReporter)
+        } else {
+            make_reporter(&matches, id.as_ref())?
+        };
+        let opts = get_run_options(&matches)?;
+        if let Some(job) = shebang_job {
+            run_command(reporter, id.as_ref(), &job, opts)?;
+        } else if let Some(cmd) = matches.value_of("shell_command") {
+            let shell = matches.value_of("shell").unwrap_or("/bin/bash");
+            run_command(reporter, id.as_ref(),
+                        &JobCommand::shell(shell, cmd, !matches.is_present("no_pipefail")),
+                        opts)?;
+        } else {
+            match matches.subcommand() {
+                (exe, Some(exe_matches)) => {
+                    let args: Vec<&str> = match exe_matches.values_of("") {
+                        Some(args) => args.collect(),
+                        None => vec![],
+                    };
+                    run_command(reporter, id.as_ref(),
+                                &JobCommand::new(exe, &args[..]), opts)?;
+                }
+                _ => {}
             }
-            _ => {}
         }
     }
 
     Ok(())
 }
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io::Write;
+
+    use uuid::Uuid;
+
+    use history::{History, RunRecord};
+    use testutils::run_record;
+    use super::{split_shebang_args, track_failures};
+
+    fn failed_record() -> RunRecord {
+        let mut record = run_record(1);
+        record.job = Some("backup".into());
+        record
+    }
+
+    fn args(items: &[&str]) -> Vec<String> {
+        items.iter().map(|x| x.to_string()).collect()
+    }
+
+    fn write_script(contents: &str) -> String {
+        let path = env::temp_dir().join(format!("cronwell-{}.sh", Uuid::new_v4().simple()));
+        File::create(&path).unwrap().write_all(contents.as_bytes()).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn test_shebang_args() {
+        let exe = env::current_exe().unwrap().to_string_lossy().into_owned();
+        let script = write_script(&format!("#!{} --job backup -- /bin/bash\n", exe));
+        let rv = split_shebang_args(args(&[
+            &exe[..], "--job backup -- /bin/bash", &script[..], "full",
+        ]));
+        fs::remove_file(&script).ok();
+        let (rv, job) = rv.unwrap();
+        assert_eq!(rv, args(&[&exe[..], "--job", "backup"]));
+        let job = job.unwrap();
+        assert_eq!(job.program, "/bin/bash");
+        assert_eq!(job.args, args(&[&script[..], "full"]));
+        assert_eq!(job.report_command(), script);
+    }
+
+    #[test]
+    fn test_argument_with_double_dash_is_not_a_shebang() {
+        let (rv, job) = split_shebang_args(args(&[
+            "cronwell", "--on-failure=notify -- oncall", "/path/script.sh",
+        ])).unwrap();
+        assert_eq!(rv, args(&["cronwell", "--on-failure=notify -- oncall", "/path/script.sh"]));
+        assert!(job.is_none());
+    }
+
+    #[test]
+    fn test_double_dash_is_not_a_shebang() {
+        let (rv, job) = split_shebang_args(args(&[
+            "cronwell", "--", "/path/script.sh",
+        ])).unwrap();
+        assert_eq!(rv, args(&["cronwell", "--", "/path/script.sh"]));
+        assert!(job.is_none());
+    }
+
+    #[test]
+    fn test_script_reexecuting_itself() {
+        let exe = env::current_exe().unwrap().to_string_lossy().into_owned();
+        let script = write_script(&format!("#!{}\necho hello\n", exe));
+        let bare = split_shebang_args(args(&[&exe[..], &script[..]]));
+        let with_option = split_shebang_args(args(&[&exe[..], "--quiet", &script[..]]));
+        fs::remove_file(&script).ok();
+        assert!(bare.is_err());
+        assert!(with_option.is_err());
+    }
+
+    #[test]
+    fn test_track_failures() {
+        let dir = env::temp_dir().join(format!("cronwell-{}", Uuid::new_v4().simple()));
+        let history = History::new(&dir);
+        let rv: Vec<_> = (0..3).map(|_| track_failures(&history, &failed_record(), 3)).collect();
+        fs::remove_dir_all(&dir).ok();
+        assert_eq!(rv, vec![(0, 3), (1, 3), (2, 3)]);
+    }
+
+    #[test]
+    fn test_track_failures_with_unwritable_state_dir() {
+        // a file in the way fails even for root, unlike permissions
+        let file = env::temp_dir().join(format!("cronwell-{}", Uuid::new_v4().simple()));
+        File::create(&file).unwrap();
+        let history = History::new(&file.join("state"));
+        let first = track_failures(&history, &failed_record(), 3);
+        let second = track_failures(&history, &failed_record(), 3);
+        fs::remove_file(&file).ok();
+        assert_eq!(first, (0, 1));
+        assert_eq!(second, (0, 1));
+    }
+}
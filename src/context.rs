@@ -0,0 +1,153 @@
+use std::env;
+use std::mem;
+use std::ptr;
+use std::fs::File;
+use std::io::Read;
+use std::ffi::{CStr, CString};
+use std::collections::BTreeMap;
+
+use libc;
+
+use api::RunContext;
+
+
+fn c_chars_to_string(chars: &[libc::c_char]) -> Option<String> {
+    let s = unsafe { CStr::from_ptr(chars.as_ptr()) };
+    let rv = s.to_string_lossy().into_owned();
+    if rv.is_empty() { None } else { Some(rv) }
+}
+
+fn read_trimmed(path: &str) -> Option<String> {
+    let mut contents = String::new();
+    match File::open(path).and_then(|mut f| f.read_to_string(&mut contents)) {
+        Ok(_) => {
+            let rv = contents.trim();
+            if rv.is_empty() { None } else { Some(rv.to_string()) }
+        }
+        Err(_) => None,
+    }
+}
+
+pub fn get_hostname() -> Option<String> {
+    let mut buf = [0 as libc::c_char; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr(), buf.len() as libc::size_t) } != 0 {
+        return None;
+    }
+    // gethostname does not guarantee termination on truncation
+    let last = buf.len() - 1;
+    buf[last] = 0;
+    c_chars_to_string(&buf)
+}
+
+fn get_fqdn(hostname: &str) -> Option<String> {
+    let node = match CString::new(hostname) {
+        Ok(node) => node,
+        Err(_) => return None,
+    };
+    unsafe {
+        let mut hints: libc::addrinfo = mem::zeroed();
+        hints.ai_family = libc::AF_UNSPEC;
+        hints.ai_flags = libc::AI_CANONNAME;
+        let mut res: *mut libc::addrinfo = ptr::null_mut();
+        if libc::getaddrinfo(node.as_ptr(), ptr::null(), &hints, &mut res) != 0 {
+            return None;
+        }
+        let rv = if !res.is_null() && !(*res).ai_canonname.is_null() {
+            Some(CStr::from_ptr((*res).ai_canonname).to_string_lossy().into_owned())
+        } else {
+            None
+        };
+        libc::freeaddrinfo(res);
+        rv
+    }
+}
+
+fn get_machine_id() -> Option<String> {
+    read_trimmed("/etc/machine-id")
+        .or_else(|| read_trimmed("/var/lib/dbus/machine-id"))
+}
+
+fn is_container_id(s: &str) -> bool {
+    s.len() == 64 && s.chars().all(|c| c.is_digit(16))
+}
+
+/// Finds the container ID in the cgroup paths of the current process.
+///
+/// Docker, containerd and the kubelet all put the 64 character container
+/// ID into the last path segments, optionally wrapped as a systemd scope
+/// (`docker-<id>.scope`).
+fn get_container_id() -> Option<String> {
+    let cgroups = match read_trimmed("/proc/self/cgroup") {
+        Some(cgroups) => cgroups,
+        None => return None,
+    };
+    for line in cgroups.lines() {
+        let path = match line.splitn(3, ':').nth(2) {
+            Some(path) => path,
+            None => continue,
+        };
+        for segment in path.rsplit('/') {
+            let segment = segment.trim_right_matches(".scope");
+            let segment = match segment.rfind('-') {
+                Some(idx) => &segment[idx + 1..],
+                None => segment,
+            };
+            if is_container_id(segment) {
+                return Some(segment.to_string());
+            }
+        }
+    }
+    None
+}
+
+fn get_uname() -> (Option<String>, Option<String>) {
+    unsafe {
+        let mut uts: libc::utsname = mem::zeroed();
+        if libc::uname(&mut uts) != 0 {
+            return (None, None);
+        }
+        (c_chars_to_string(&uts.sysname[..]), c_chars_to_string(&uts.release[..]))
+    }
+}
+
+fn get_username(uid: libc::uid_t) -> Option<String> {
+    unsafe {
+        let pw = libc::getpwuid(uid);
+        if !pw.is_null() && !(*pw).pw_name.is_null() {
+            return Some(CStr::from_ptr((*pw).pw_name).to_string_lossy().into_owned());
+        }
+    }
+    env::var("USER").ok()
+}
+
+/// Collects information about the host and user the job runs as.
+///
+/// Only environment variables named in `env_allowlist` are captured as
+/// the environment is likely to contain secrets.
+pub fn collect_context(env_allowlist: &[&str]) -> RunContext {
+    let hostname = get_hostname();
+    let fqdn = hostname.as_ref().and_then(|x| get_fqdn(x));
+    let (os, kernel) = get_uname();
+    let uid = unsafe { libc::getuid() };
+
+    let mut captured_env = BTreeMap::new();
+    for key in env_allowlist {
+        if let Ok(value) = env::var(key) {
+            captured_env.insert(key.to_string(), value);
+        }
+    }
+
+    RunContext {
+        hostname: hostname,
+        fqdn: fqdn,
+        machine_id: get_machine_id(),
+        container_id: get_container_id(),
+        os: os,
+        kernel: kernel,
+        user: get_username(uid),
+        uid: uid as u32,
+        cwd: env::current_dir().ok().map(|x| x.to_string_lossy().into_owned()),
+        cronwell_version: env!("CARGO_PKG_VERSION").to_string(),
+        env: captured_env,
+    }
+}
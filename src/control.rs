@@ -0,0 +1,159 @@
+use std::io;
+use std::fs::File;
+use std::io::Write;
+use std::collections::BTreeMap;
+use std::os::unix::io::{RawFd, FromRawFd, IntoRawFd};
+
+use libc;
+use serde_json;
+use serde_json::Value;
+
+use api::RunStep;
+
+
+/// A message sent by the wrapped process over the control channel.
+#[derive(Debug)]
+pub enum ControlMessage {
+    Progress(f64),
+    Tags(BTreeMap<String, String>),
+    Status(String),
+    /// A nested cronwell finished and reports itself as a step.
+    Step(RunStep),
+}
+
+/// The state accumulated from all control messages of a run.
+#[derive(Default, Debug)]
+pub struct ControlState {
+    pub progress: Option<f64>,
+    pub tags: BTreeMap<String, String>,
+    pub status: Option<String>,
+}
+
+/// A pipe the wrapped process inherits to talk back to cronwell.
+///
+/// The write end is announced to the child in `CRONWELL_CONTROL_FD` and
+/// accepts one JSON object per line.
+pub struct ControlChannel {
+    read_fd: RawFd,
+    write_fd: Option<RawFd>,
+}
+
+
+fn value_to_string(value: &Value) -> String {
+    match *value {
+        Value::String(ref s) => s.clone(),
+        ref other => serde_json::to_string(other).unwrap_or(String::new()),
+    }
+}
+
+/// Parses a single line written to the control channel.  A line can
+/// carry more than one instruction so this returns all of them.
+pub fn parse_control_line(line: &str) -> Vec<ControlMessage> {
+    let mut rv = vec![];
+    let value: Value = match serde_json::from_str(line) {
+        Ok(value) => value,
+        Err(_) => return rv,
+    };
+    if let Some(progress) = value.find("progress").and_then(|x| x.as_f64()) {
+        rv.push(ControlMessage::Progress(progress));
+    }
+    if let Some(tags) = value.find("tag").and_then(|x| x.as_object()) {
+        rv.push(ControlMessage::Tags(tags.iter()
+            .map(|(k, v)| (k.clone(), value_to_string(v)))
+            .collect()));
+    }
+    if let Some(status) = value.find("status").and_then(|x| x.as_string()) {
+        rv.push(ControlMessage::Status(status.to_string()));
+    }
+    if let Some(step) = value.find("step") {
+        if let Ok(step) = serde_json::from_value(step.clone()) {
+            rv.push(ControlMessage::Step(step));
+        }
+    }
+    rv
+}
+
+/// Reports a nested run as a step over the control channel of the
+/// cronwell we are running below.  The outer run sends it on to its
+/// monitor, so the nested run never needs the monitor's credentials.
+pub fn send_step(fd: RawFd, step: &RunStep) -> io::Result<()> {
+    let mut line = serde_json::to_vec(&{
+        let mut rv = BTreeMap::new();
+        rv.insert("step".to_string(), serde_json::to_value(step));
+        Value::Object(rv)
+    }).map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+    line.push(b'\n');
+    let mut f = unsafe { File::from_raw_fd(fd) };
+    let rv = f.write_all(&line);
+    // the descriptor belongs to the outer run, keep it open
+    f.into_raw_fd();
+    rv
+}
+
+
+impl ControlState {
+    pub fn apply(&mut self, msg: ControlMessage) {
+        match msg {
+            ControlMessage::Progress(progress) => {
+                self.progress = Some(progress);
+            }
+            ControlMessage::Tags(tags) => {
+                self.tags.extend(tags);
+            }
+            ControlMessage::Status(status) => {
+                self.status = Some(status);
+            }
+            // steps are not part of the state, they are reported as is
+            ControlMessage::Step(_) => {}
+        }
+    }
+
+    /// Returns `true` if the process told us that it failed regardless
+    /// of what its exit status is going to be.
+    pub fn reported_failure(&self) -> bool {
+        match self.status.as_ref().map(|x| &x[..]) {
+            Some("error") | Some("failed") => true,
+            _ => false,
+        }
+    }
+
+    pub fn tags(&self) -> Option<BTreeMap<String, String>> {
+        if self.tags.is_empty() { None } else { Some(self.tags.clone()) }
+    }
+}
+
+impl ControlChannel {
+    pub fn new() -> io::Result<ControlChannel> {
+        let mut fds = [0 as libc::c_int; 2];
+        unsafe {
+            if libc::pipe(fds.as_mut_ptr()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // only the write end must survive the exec into the child
+            libc::fcntl(fds[0], libc::F_SETFD, libc::FD_CLOEXEC);
+        }
+        Ok(ControlChannel {
+            read_fd: fds[0],
+            write_fd: Some(fds[1]),
+        })
+    }
+
+    /// The file descriptor number to announce to the child.
+    pub fn child_fd(&self) -> Option<RawFd> {
+        self.write_fd
+    }
+
+    /// Closes our copy of the write end after the child was spawned so
+    /// that the reader sees EOF once the child exits.
+    pub fn close_child_end(&mut self) {
+        if let Some(fd) = self.write_fd.take() {
+            unsafe { libc::close(fd); }
+        }
+    }
+
+    /// Converts the channel into a reader for the messages.
+    pub fn into_reader(mut self) -> File {
+        self.close_child_end();
+        unsafe { File::from_raw_fd(self.read_fd) }
+    }
+}
@@ -0,0 +1,568 @@
+use std::env;
+use std::collections::HashSet;
+
+use error::Error;
+
+
+/// A single line of a crontab.
+pub enum CrontabLine {
+    /// Empty lines and comments which are kept as they are.
+    Other(String),
+    /// An environment assignment (`PATH=/usr/bin`).
+    Env(EnvLine),
+    /// A scheduled job.
+    Job(CronJob),
+    /// A line that could not be parsed.
+    Invalid(InvalidLine),
+}
+
+pub struct InvalidLine {
+    pub lineno: usize,
+    pub error: String,
+    pub raw: String,
+}
+
+pub struct EnvLine {
+    pub name: String,
+    pub value: String,
+    pub raw: String,
+}
+
+pub struct CronJob {
+    /// The line number in the crontab (1 based).
+    pub lineno: usize,
+    /// The schedule, either five fields or an `@` nickname.
+    pub schedule: String,
+    /// The user field of system crontabs.
+    pub user: Option<String>,
+    /// The command including cron's `%` stdin section.
+    pub command: String,
+    /// The schedule and user part of the line as written.
+    prefix: String,
+    pub raw: String,
+}
+
+pub struct Crontab {
+    pub lines: Vec<CrontabLine>,
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LintLevel {
+    Warning,
+    Error,
+}
+
+/// A problem found in a crontab.
+pub struct Lint {
+    pub lineno: usize,
+    pub level: LintLevel,
+    pub message: String,
+}
+
+
+/// Names the cronwell binary is installed under.
+const BINARY_NAMES: &'static [&'static str] = &["cronwell", env!("CARGO_PKG_NAME")];
+
+const NICKNAMES: &'static [(&'static str, Option<&'static str>)] = &[
+    ("@reboot", None),
+    ("@yearly", Some("0 0 1 1 *")),
+    ("@annually", Some("0 0 1 1 *")),
+    ("@monthly", Some("0 0 1 * *")),
+    ("@weekly", Some("0 0 * * 0")),
+    ("@daily", Some("0 0 * * *")),
+    ("@midnight", Some("0 0 * * *")),
+    ("@hourly", Some("0 * * * *")),
+];
+
+/// Splits off `n` whitespace separated fields and returns them together
+/// with the untouched rest of the line.
+fn split_fields(line: &str, n: usize) -> Option<(Vec<&str>, &str)> {
+    let mut fields = vec![];
+    let mut rest = line.trim_left();
+    while fields.len() < n {
+        if rest.is_empty() {
+            return None;
+        }
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        fields.push(&rest[..end]);
+        rest = rest[end..].trim_left();
+    }
+    Some((fields, rest))
+}
+
+fn is_env_name(s: &str) -> bool {
+    !s.is_empty() &&
+        !s.chars().next().unwrap().is_digit(10) &&
+        s.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+fn parse_env_line(line: &str) -> Option<EnvLine> {
+    let idx = match line.find('=') {
+        Some(idx) => idx,
+        None => return None,
+    };
+    let name = line[..idx].trim();
+    if !is_env_name(name) {
+        return None;
+    }
+    let mut value = line[idx + 1..].trim();
+    if value.len() >= 2 && (value.starts_with('"') && value.ends_with('"') ||
+                            value.starts_with('\'') && value.ends_with('\'')) {
+        value = &value[1..value.len() - 1];
+    }
+    Some(EnvLine {
+        name: name.to_string(),
+        value: value.to_string(),
+        raw: line.to_string(),
+    })
+}
+
+fn parse_job_line(lineno: usize, line: &str, system: bool) -> Result<CronJob, String> {
+    let schedule_fields = if line.trim_left().starts_with('@') { 1 } else { 5 };
+    let total_fields = schedule_fields + if system { 1 } else { 0 };
+    let (fields, command) = match split_fields(line, total_fields) {
+        Some(rv) => rv,
+        None => return Err("incomplete crontab entry".into()),
+    };
+    if command.is_empty() {
+        return Err("missing command".into());
+    }
+    Ok(CronJob {
+        lineno: lineno,
+        schedule: fields[..schedule_fields].join(" "),
+        user: if system { Some(fields[schedule_fields].to_string()) } else { None },
+        command: command.to_string(),
+        prefix: line[..line.len() - command.len()].to_string(),
+        raw: line.to_string(),
+    })
+}
+
+fn parse_value(value: &str, min: u32, max: u32,
+               names: &[&str]) -> Result<u32, String> {
+    if let Ok(num) = value.parse::<u32>() {
+        if num < min || num > max {
+            return Err(format!("{} is out of range {}-{}", num, min, max));
+        }
+        return Ok(num);
+    }
+    let lower = value.to_lowercase();
+    match names.iter().position(|x| *x == lower) {
+        Some(idx) => Ok(idx as u32 + min),
+        None => Err(format!("invalid value '{}'", value)),
+    }
+}
+
+fn validate_field(field: &str, min: u32, max: u32,
+                  names: &[&str]) -> Result<(), String> {
+    for item in field.split(',') {
+        let (range, step) = match item.find('/') {
+            Some(idx) => (&item[..idx], Some(&item[idx + 1..])),
+            None => (item, None),
+        };
+        if let Some(step) = step {
+            match step.parse::<u32>() {
+                Ok(step) if step > 0 => {}
+                _ => return Err(format!("invalid step '{}'", step)),
+            }
+        }
+        if range == "*" {
+            continue;
+        }
+        match range.find('-') {
+            Some(idx) => {
+                let start = parse_value(&range[..idx], min, max, names)?;
+                let end = parse_value(&range[idx + 1..], min, max, names)?;
+                if start > end {
+                    return Err(format!("invalid range '{}'", range));
+                }
+            }
+            None => {
+                parse_value(range, min, max, names)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks the syntax of a schedule (five fields or an `@` nickname).
+pub fn validate_schedule(schedule: &str) -> Result<(), String> {
+    if schedule.starts_with('@') {
+        if NICKNAMES.iter().any(|x| x.0 == schedule) {
+            return Ok(());
+        }
+        return Err(format!("unknown schedule '{}'", schedule));
+    }
+    const MONTHS: &'static [&'static str] = &[
+        "jan", "feb", "mar", "apr", "may", "jun",
+        "jul", "aug", "sep", "oct", "nov", "dec"];
+    const DAYS: &'static [&'static str] = &[
+        "sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+    let fields: Vec<&str> = schedule.split_whitespace().collect();
+    if fields.len() != 5 {
+        return Err(format!("expected 5 schedule fields, got {}", fields.len()));
+    }
+    let specs = [
+        ("minute", 0, 59, &[][..]),
+        ("hour", 0, 23, &[][..]),
+        ("day of month", 1, 31, &[][..]),
+        ("month", 1, 12, MONTHS),
+        ("day of week", 0, 7, DAYS),
+    ];
+    for (field, &(name, min, max, names)) in fields.iter().zip(specs.iter()) {
+        validate_field(field, min, max, names)
+            .map_err(|err| format!("{}: {}", name, err))?;
+    }
+    Ok(())
+}
+
+/// Returns `true` if all output of the command is thrown away which
+/// also hides the error messages of failing runs.
+fn discards_output(command: &str) -> bool {
+    let compact: String = command.chars().filter(|c| !c.is_whitespace()).collect();
+    compact.contains("&>/dev/null") ||
+        (compact.contains(">/dev/null") && compact.contains("2>&1")) ||
+        compact.contains("2>/dev/null")
+}
+
+/// Finds the first `%` not escaped with a backslash.  Cron turns it into
+/// a newline and feeds everything after it to the command's stdin.
+pub fn find_unescaped_percent(command: &str) -> Option<usize> {
+    let mut escaped = false;
+    for (idx, c) in command.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == '%' {
+            return Some(idx);
+        }
+    }
+    None
+}
+
+/// Returns `true` if the program is cronwell, either by one of the names
+/// it is installed under or by the name of the running executable, which
+/// is what import writes into the crontab.
+pub fn is_cronwell_binary(program: &str) -> bool {
+    let basename = program.rsplit('/').next().unwrap_or(program);
+    if BINARY_NAMES.contains(&basename) {
+        return true;
+    }
+    env::current_exe().ok()
+        .and_then(|x| x.file_name().map(|x| x.to_string_lossy().into_owned()))
+        .map(|x| x == basename)
+        .unwrap_or(false)
+}
+
+/// Builds the cronwell invocation that import puts in place of a command.
+/// The monitor token is read from `monitor_file`.  Imported jobs keep
+/// cron's semantics: they run in the `SHELL` of the crontab, or `/bin/sh`
+/// like cron does, and without pipefail.
+pub fn wrap_command(cronwell: &str, monitor_file: &str, slug: &str, shell: Option<&str>,
+                    cmd: &str) -> String {
+    format!("{} --monitor-file {} --job {} --shell {} --no-pipefail -c {}", cronwell,
+            shell_quote(monitor_file), slug, shell_quote(shell.unwrap_or("/bin/sh")),
+            shell_quote(cmd))
+}
+
+/// Quotes a string for a POSIX shell.
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace("'", "'\\''"))
+}
+
+/// Derives a monitor slug from a command.  This uses the name of the
+/// program that is run, skipping leading variable assignments and
+/// `cd ... &&` prefixes.
+pub fn derive_slug(command: &str) -> String {
+    let mut words = command.split_whitespace();
+    let mut program = "job";
+    while let Some(word) = words.next() {
+        if word.contains('=') && is_env_name(&word[..word.find('=').unwrap()]) {
+            continue;
+        }
+        if word == "cd" {
+            while let Some(word) = words.next() {
+                if word == "&&" || word == ";" || word.ends_with(';') {
+                    break;
+                }
+            }
+            continue;
+        }
+        if word == "sudo" || word == "nice" || word == "ionice" || word == "exec" {
+            continue;
+        }
+        program = word;
+        break;
+    }
+
+    let basename = program.rsplit('/').next().unwrap_or(program);
+    let stem = match basename.rfind('.') {
+        Some(idx) if idx > 0 => &basename[..idx],
+        _ => basename,
+    };
+    let mut rv = String::new();
+    for c in stem.chars() {
+        if c.is_alphanumeric() {
+            rv.extend(c.to_lowercase());
+        } else if !rv.ends_with('-') {
+            rv.push('-');
+        }
+    }
+    let rv = rv.trim_matches('-').to_string();
+    if rv.is_empty() { "job".to_string() } else { rv }
+}
+
+
+impl CronJob {
+    /// Returns `true` if the command is already run through cronwell.
+    pub fn is_monitored(&self) -> bool {
+        self.command.split_whitespace().next()
+            .map(is_cronwell_binary)
+            .unwrap_or(false)
+    }
+
+    /// The schedule in five field crontab syntax.  Returns `None` for
+    /// `@reboot` which has no schedule.
+    pub fn crontab_schedule(&self) -> Option<String> {
+        if self.schedule.starts_with('@') {
+            NICKNAMES.iter()
+                .find(|x| x.0 == self.schedule)
+                .and_then(|x| x.1)
+                .map(|x| x.to_string())
+        } else {
+            Some(self.schedule.clone())
+        }
+    }
+
+    /// Returns the line with the command wrapped by `wrapper`.  The stdin
+    /// section after an unescaped `%` stays outside of the wrapped part
+    /// so that cron still handles it.
+    pub fn rewrite<F: FnOnce(&str) -> String>(&self, wrapper: F) -> String {
+        let (cmd, stdin) = match find_unescaped_percent(&self.command) {
+            Some(idx) => (&self.command[..idx], &self.command[idx..]),
+            None => (&self.command[..], ""),
+        };
+        format!("{}{}{}", self.prefix, wrapper(cmd.trim_right()), stdin)
+    }
+}
+
+impl CrontabLine {
+    pub fn raw(&self) -> &str {
+        match *self {
+            CrontabLine::Other(ref raw) => raw,
+            CrontabLine::Env(ref env) => &env.raw,
+            CrontabLine::Job(ref job) => &job.raw,
+            CrontabLine::Invalid(ref invalid) => &invalid.raw,
+        }
+    }
+}
+
+impl Crontab {
+    /// Parses a crontab.  System crontabs (`/etc/crontab`, `/etc/cron.d`)
+    /// carry a user field between schedule and command.
+    pub fn parse(contents: &str, system: bool) -> Crontab {
+        let mut lines = vec![];
+        for (idx, line) in contents.lines().enumerate() {
+            let trimmed = line.trim();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                lines.push(CrontabLine::Other(line.to_string()));
+            } else if let Some(env) = parse_env_line(line) {
+                lines.push(CrontabLine::Env(env));
+            } else {
+                lines.push(match parse_job_line(idx + 1, line, system) {
+                    Ok(job) => CrontabLine::Job(job),
+                    Err(err) => CrontabLine::Invalid(InvalidLine {
+                        lineno: idx + 1,
+                        error: err,
+                        raw: line.to_string(),
+                    }),
+                });
+            }
+        }
+        Crontab { lines: lines }
+    }
+
+    pub fn jobs(&self) -> Vec<&CronJob> {
+        self.lines.iter().filter_map(|line| match *line {
+            CrontabLine::Job(ref job) => Some(job),
+            _ => None,
+        }).collect()
+    }
+
+    pub fn env(&self, name: &str) -> Option<&str> {
+        self.lines.iter().filter_map(|line| match *line {
+            CrontabLine::Env(ref env) if env.name == name => Some(&env.value[..]),
+            _ => None,
+        }).last()
+    }
+
+    /// Assigns a unique slug to every job that is not yet monitored.
+    /// The result is indexed like `jobs()`.
+    pub fn assign_slugs(&self) -> Vec<Option<String>> {
+        let mut seen = HashSet::new();
+        self.jobs().iter().map(|job| {
+            if job.is_monitored() {
+                return None;
+            }
+            let base = derive_slug(&job.command);
+            let mut slug = base.clone();
+            let mut counter = 1;
+            while seen.contains(&slug) {
+                counter += 1;
+                slug = format!("{}-{}", base, counter);
+            }
+            seen.insert(slug.clone());
+            Some(slug)
+        }).collect()
+    }
+
+    /// Rewrites every job that is not monitored yet with `rewrite`, which
+    /// gets the job and its slug and returns the new line.  Returns the
+    /// lines before and after.
+    pub fn rewrite_jobs<F>(&self, mut rewrite: F) -> Result<(Vec<String>, Vec<String>), Error>
+        where F: FnMut(&CronJob, &str) -> Result<String, Error>
+    {
+        let slugs = self.assign_slugs();
+        let mut old_lines = vec![];
+        let mut new_lines = vec![];
+        let mut job_idx = 0;
+        for line in &self.lines {
+            old_lines.push(line.raw().to_string());
+            let job = match *line {
+                CrontabLine::Job(ref job) => job,
+                _ => {
+                    new_lines.push(line.raw().to_string());
+                    continue;
+                }
+            };
+            let slug = slugs[job_idx].clone();
+            job_idx += 1;
+            match slug {
+                Some(slug) => new_lines.push(rewrite(job, &slug)?),
+                None => new_lines.push(job.raw.clone()),
+            }
+        }
+        Ok((old_lines, new_lines))
+    }
+
+    /// Checks the crontab for invalid entries, jobs that are not run
+    /// through cronwell and patterns that commonly hide failures.
+    pub fn lint(&self) -> Vec<Lint> {
+        let mut rv = vec![];
+        {
+            let mut lint = |lineno, level, message: String| {
+                rv.push(Lint { lineno: lineno, level: level, message: message });
+            };
+
+            if self.jobs().len() > 0 && self.env("PATH").is_none() {
+                lint(0, LintLevel::Warning,
+                     "no PATH set, cron's default PATH is very minimal".into());
+            }
+
+            for line in &self.lines {
+                let job = match *line {
+                    CrontabLine::Job(ref job) => job,
+                    CrontabLine::Invalid(ref invalid) => {
+                        lint(invalid.lineno, LintLevel::Error, invalid.error.clone());
+                        continue;
+                    }
+                    _ => continue,
+                };
+                if let Err(err) = validate_schedule(&job.schedule) {
+                    lint(job.lineno, LintLevel::Error,
+                         format!("invalid schedule: {}", err));
+                }
+                if !job.is_monitored() {
+                    lint(job.lineno, LintLevel::Warning,
+                         "job is not monitored by cronwell".into());
+                }
+                if find_unescaped_percent(&job.command).is_some() {
+                    lint(job.lineno, LintLevel::Warning,
+                         "unescaped % in command is turned into a newline by cron".into());
+                }
+                if discards_output(&job.command) {
+                    lint(job.lineno, LintLevel::Warning,
+                         "output is sent to /dev/null which hides failures".into());
+                }
+            }
+        }
+        rv
+    }
+}
+
+/// Renders a minimal diff between two versions of a crontab with the
+/// same number of lines.
+pub fn line_diff(name: &str, old: &[String], new: &[String]) -> String {
+    let mut rv = format!("--- {}\n+++ {}\n", name, name);
+    for (idx, (a, b)) in old.iter().zip(new.iter()).enumerate() {
+        if a != b {
+            rv.push_str(&format!("@@ line {} @@\n-{}\n+{}\n", idx + 1, a, b));
+        }
+    }
+    rv
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::{Crontab, validate_schedule, wrap_command};
+
+    fn import(contents: &str) -> String {
+        let cronwell = env::current_exe().unwrap().to_string_lossy().into_owned();
+        let crontab = Crontab::parse(contents, false);
+        let (_, new_lines) = crontab.rewrite_jobs(|job, slug| {
+            Ok(job.rewrite(|cmd| {
+                wrap_command(&cronwell, &format!("/etc/cronwell/crontab-{}.env", slug),
+                             slug, crontab.env("SHELL"), cmd)
+            }))
+        }).unwrap();
+        new_lines.join("\n")
+    }
+
+    #[test]
+    fn test_import_round_trip() {
+        let original = "PATH=/usr/bin:/bin\n\
+                        SHELL=/bin/bash\n\
+                        0 3 * * * /usr/local/bin/backup.sh --full\n\
+                        */5 * * * * cd /srv && ./sync.py%input\n";
+        let imported = import(original);
+        assert!(imported.contains("--monitor-file '/etc/cronwell/crontab-backup.env' \
+                                   --job backup --shell '/bin/bash' --no-pipefail \
+                                   -c '/usr/local/bin/backup.sh --full'"));
+        assert!(!imported.contains(" -m "));
+        assert!(imported.contains("--job sync --shell '/bin/bash' --no-pipefail \
+                                   -c 'cd /srv && ./sync.py'%input"));
+
+        // a second import leaves the wrapped lines alone
+        assert_eq!(import(&imported), imported);
+        assert!(Crontab::parse(&imported, false).assign_slugs().iter().all(|x| x.is_none()));
+
+        let lints = Crontab::parse(&imported, false).lint();
+        assert!(lints.iter().all(|x| !x.message.contains("not monitored")),
+                "unexpected lints: {:?}", lints.iter().map(|x| &x.message).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_validate_schedule() {
+        for schedule in &["* * * * *", "*/5 * * * *", "0 3 1-15 jan-mar mon,wed,fri",
+                          "0-30/10 * * * SUN", "0 0 * * 7", "@daily", "@reboot"] {
+            assert!(validate_schedule(schedule).is_ok(), "rejected {}", schedule);
+        }
+    }
+
+    #[test]
+    fn test_validate_schedule_errors() {
+        let error = |schedule: &str| validate_schedule(schedule).unwrap_err();
+        assert_eq!(error("* * * *"), "expected 5 schedule fields, got 4");
+        assert_eq!(error("@fortnightly"), "unknown schedule '@fortnightly'");
+        assert_eq!(error("60 * * * *"), "minute: 60 is out of range 0-59");
+        assert_eq!(error("* * 0 * *"), "day of month: 0 is out of range 1-31");
+        assert_eq!(error("* * * foo *"), "month: invalid value 'foo'");
+        assert_eq!(error("* * * * jan"), "day of week: invalid value 'jan'");
+        assert_eq!(error("*/0 * * * *"), "minute: invalid step '0'");
+        assert_eq!(error("30-10 * * * *"), "minute: invalid range '30-10'");
+        assert_eq!(error("1,,2 * * * *"), "minute: invalid value ''");
+    }
+}
@@ -7,6 +7,7 @@ use std::error;
 use api;
 
 use clap;
+use regex;
 
 #[derive(Debug)]
 pub struct Error {
@@ -16,6 +17,7 @@ pub struct Error {
 #[derive(Debug)]
 enum ErrorRepr {
     ClapError(clap::Error),
+    ApiError(api::Error),
     BasicError(String),
 }
 
@@ -32,7 +34,7 @@ macro_rules! basic_error {
 }
 
 basic_error!(io::Error, "io error");
-basic_error!(api::Error, "could not perform API request");
+basic_error!(regex::Error, "invalid pattern");
 
 impl From<clap::Error> for Error {
     fn from(err: clap::Error) -> Error {
@@ -42,6 +44,14 @@ impl From<clap::Error> for Error {
     }
 }
 
+impl From<api::Error> for Error {
+    fn from(err: api::Error) -> Error {
+        Error {
+            repr: ErrorRepr::ApiError(err)
+        }
+    }
+}
+
 impl From<String> for Error {
     fn from(err: String) -> Error {
         Error {
@@ -64,6 +74,10 @@ impl Error {
     pub fn exit(&self) -> ! {
         match self.repr {
             ErrorRepr::ClapError(ref err) => err.exit(),
+            ErrorRepr::ApiError(ref err) => {
+                writeln!(&mut io::stderr(), "error: {}", self).ok();
+                process::exit(err.exit_code())
+            },
             _ => {
                 writeln!(&mut io::stderr(), "error: {}", self).ok();
                 process::exit(1)
@@ -77,6 +91,9 @@ impl fmt::Display for Error {
         match self.repr {
             ErrorRepr::BasicError(ref msg) => write!(f, "{}", msg),
             ErrorRepr::ClapError(ref err) => write!(f, "{}", err),
+            ErrorRepr::ApiError(ref err) => {
+                write!(f, "could not perform API request: {}", err)
+            }
         }
     }
 }
@@ -86,6 +103,7 @@ impl error::Error for Error {
         match self.repr {
             ErrorRepr::BasicError(ref msg) => &msg,
             ErrorRepr::ClapError(ref err) => err.description(),
+            ErrorRepr::ApiError(_) => "could not perform API request",
         }
     }
 
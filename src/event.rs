@@ -0,0 +1,283 @@
+use std::env;
+use std::str::FromStr;
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use serde_json;
+use serde_json::Value;
+use url::Url;
+use uuid::Uuid;
+
+use api::{Api, Attachment, Method, RunContext};
+use error::Error;
+use history::RunRecord;
+use run::RunInfo;
+
+
+/// Sentry only keeps the most recent breadcrumbs of an event.
+const MAX_BREADCRUMBS: usize = 100;
+
+/// A Sentry DSN resolved to the endpoints of its project.
+#[derive(Clone, Debug)]
+pub struct Dsn {
+    store_url: String,
+    envelope_url: String,
+    public_key: String,
+    secret_key: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Breadcrumb {
+    pub timestamp: f64,
+    pub category: String,
+    pub level: String,
+    pub message: String,
+}
+
+#[derive(Serialize)]
+pub struct Breadcrumbs {
+    pub values: Vec<Breadcrumb>,
+}
+
+#[derive(Serialize)]
+pub struct User {
+    pub id: String,
+    pub username: Option<String>,
+}
+
+/// An error event as accepted by the store endpoint.
+#[derive(Serialize)]
+pub struct Event {
+    pub event_id: String,
+    pub timestamp: f64,
+    pub platform: String,
+    pub level: String,
+    pub logger: String,
+    pub message: String,
+    pub server_name: Option<String>,
+    pub release: String,
+    pub fingerprint: Vec<String>,
+    pub tags: BTreeMap<String, String>,
+    pub user: User,
+    pub breadcrumbs: Breadcrumbs,
+    pub contexts: BTreeMap<String, Value>,
+}
+
+
+fn object(items: Vec<(&str, Value)>) -> Value {
+    let mut rv = BTreeMap::new();
+    for (key, value) in items {
+        rv.insert(key.to_string(), value);
+    }
+    Value::Object(rv)
+}
+
+fn optional_string(value: Option<&String>) -> Value {
+    value.map(|x| Value::String(x.clone())).unwrap_or(Value::Null)
+}
+
+fn serialize<S: Serialize>(value: &S) -> Result<Vec<u8>, Error> {
+    Ok(serde_json::to_vec(value).map_err(|err| format!("could not serialize event: {}", err))?)
+}
+
+/// Returns the DSN to send error events to, if one is configured.
+pub fn get_dsn(value: Option<&str>) -> Result<Option<Dsn>, Error> {
+    if let Some(value) = value {
+        return Ok(Some(value.parse()?));
+    }
+    match env::var("SENTRY_DSN") {
+        Ok(ref val) if !val.is_empty() => Ok(Some(val.parse()?)),
+        _ => Ok(None),
+    }
+}
+
+
+impl Dsn {
+    pub fn store_url(&self) -> &str {
+        &self.store_url
+    }
+
+    pub fn envelope_url(&self) -> &str {
+        &self.envelope_url
+    }
+
+    /// The value of the `X-Sentry-Auth` header.
+    pub fn auth_header(&self) -> String {
+        let mut rv = format!("Sentry sentry_version=7, sentry_client=cronwell/{}, sentry_key={}",
+                             env!("CARGO_PKG_VERSION"), self.public_key);
+        if let Some(ref secret_key) = self.secret_key {
+            rv.push_str(&format!(", sentry_secret={}", secret_key));
+        }
+        rv
+    }
+}
+
+impl FromStr for Dsn {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Dsn, Error> {
+        let url = Url::parse(s).map_err(|_| "Malformed DSN")?;
+        if url.scheme() != "http" && url.scheme() != "https" {
+            fail!("Unsupported DSN: bad scheme {}", url.scheme());
+        }
+        if url.username().is_empty() {
+            fail!("Unsupported DSN: missing public key");
+        }
+        let host = url.host_str().ok_or("Unsupported DSN: missing host")?;
+        let path = url.path().trim_right_matches('/');
+        let (prefix, project_id) = match path.rfind('/') {
+            Some(idx) if idx + 1 < path.len() => (&path[..idx], &path[idx + 1..]),
+            _ => fail!("Unsupported DSN: missing project ID"),
+        };
+        let port = match url.port() {
+            Some(port) => format!(":{}", port),
+            None => String::new(),
+        };
+        let base = format!("{}://{}{}{}/api/{}", url.scheme(), host, port, prefix, project_id);
+        Ok(Dsn {
+            store_url: format!("{}/store/", base),
+            envelope_url: format!("{}/envelope/", base),
+            public_key: url.username().to_string(),
+            secret_key: url.password().map(|x| x.to_string()),
+        })
+    }
+}
+
+impl Event {
+    /// Builds the error event for a failed run.
+    ///
+    /// Events are grouped per job, or monitor name, and exit code.  The
+    /// run ID is attached so the event can be found from the failed
+    /// check-in and the other way round.
+    pub fn from_failure(run: &RunInfo, record: &RunRecord, args: &[&str],
+                        lines: &[String], context: &RunContext) -> Event {
+        let mut tags = BTreeMap::new();
+        tags.insert("exit_code".to_string(), record.status.to_string());
+        tags.insert("cronwell.run_id".to_string(), record.run_id.clone());
+        if let Some(ref job) = record.job {
+            tags.insert("job".to_string(), job.clone());
+        }
+        if let Some(ref hostname) = context.hostname {
+            tags.insert("host".to_string(), hostname.clone());
+        }
+        if let Some(ref user) = context.user {
+            tags.insert("user".to_string(), user.clone());
+        }
+
+        let skip = lines.len().saturating_sub(MAX_BREADCRUMBS);
+        let breadcrumbs = lines[skip..].iter().map(|line| {
+            // lines that matched a fail pattern are rendered with a marker
+            let level = if line.starts_with(">>> ") { "error" } else { "info" };
+            Breadcrumb {
+                timestamp: record.end,
+                category: "output".into(),
+                level: level.into(),
+                message: line.clone(),
+            }
+        }).collect();
+
+        let mut contexts = BTreeMap::new();
+        contexts.insert("trace".to_string(), object(vec![
+            ("type", Value::String("trace".into())),
+            ("trace_id", Value::String(run.trace_id.clone())),
+            ("span_id", Value::String(run.span_id.clone())),
+        ]));
+        contexts.insert("monitor".to_string(), object(vec![
+            ("check_in_id", Value::String(record.run_id.clone())),
+            ("monitor_name", Value::String(record.monitor.clone())),
+            ("job", optional_string(record.job.as_ref())),
+        ]));
+        contexts.insert("command".to_string(), object(vec![
+            ("command", Value::String(record.command.clone())),
+            ("args", Value::Array(args.iter().map(|x| Value::String(x.to_string())).collect())),
+            ("exit_code", Value::I64(record.status as i64)),
+            ("duration", Value::F64(record.duration)),
+            ("cwd", optional_string(context.cwd.as_ref())),
+        ]));
+
+        Event {
+            event_id: Uuid::new_v4().simple().to_string(),
+            timestamp: record.end,
+            platform: "other".into(),
+            level: "error".into(),
+            logger: "cronwell".into(),
+            message: format!("{} exited with status {}", record.command, record.status),
+            server_name: context.hostname.clone(),
+            release: format!("cronwell@{}", context.cronwell_version),
+            fingerprint: vec![
+                "cronwell".into(),
+                record.key().to_string(),
+                record.status.to_string(),
+            ],
+            tags: tags,
+            user: User {
+                id: context.uid.to_string(),
+                username: context.user.clone(),
+            },
+            breadcrumbs: Breadcrumbs { values: breadcrumbs },
+            contexts: contexts,
+        }
+    }
+
+    /// Sends the event to the DSN.  Events with an attachment go to the
+    /// envelope endpoint as the store endpoint only takes the event.
+    pub fn send(&self, dsn: &Dsn, attachment: Option<&Attachment>) -> Result<(), Error> {
+        let (url, body, content_type) = match attachment {
+            Some(attachment) => {
+                (dsn.envelope_url(), self.to_envelope(attachment)?,
+                 "application/x-sentry-envelope")
+            }
+            None => {
+                (dsn.store_url(), serialize(self)?, "application/json")
+            }
+        };
+        Api::plain().request(Method::Post, url)?
+            .with_header("X-Sentry-Auth", &dsn.auth_header())?
+            .with_body(body, content_type)?
+            .send()?
+            .to_result()?;
+        Ok(())
+    }
+
+    fn to_envelope(&self, attachment: &Attachment) -> Result<Vec<u8>, Error> {
+        let event = serialize(self)?;
+        let mut rv = serialize(&object(vec![
+            ("event_id", Value::String(self.event_id.clone())),
+        ]))?;
+        rv.push(b'\n');
+        rv.extend(serialize(&object(vec![
+            ("type", Value::String("event".into())),
+            ("length", Value::U64(event.len() as u64)),
+        ]))?);
+        rv.push(b'\n');
+        rv.extend(event);
+        rv.push(b'\n');
+        rv.extend(serialize(&object(vec![
+            ("type", Value::String("attachment".into())),
+            ("length", Value::U64(attachment.data.len() as u64)),
+            ("filename", Value::String(attachment.filename.clone())),
+            ("content_type", Value::String(attachment.content_type.clone())),
+            ("attachment_type", Value::String("event.attachment".into())),
+        ]))?);
+        rv.push(b'\n');
+        rv.extend_from_slice(&attachment.data);
+        rv.push(b'\n');
+        Ok(rv)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use context::collect_context;
+    use run::RunInfo;
+    use testutils::run_record;
+    use super::Event;
+
+    #[test]
+    fn test_event_fingerprint() {
+        let event = Event::from_failure(&RunInfo::from_env(), &run_record(2), &["backup.sh"],
+                                        &[], &collect_context(&[]));
+        assert_eq!(event.fingerprint, vec!["cronwell", "nightly-backup", "2"]);
+    }
+}
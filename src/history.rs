@@ -0,0 +1,178 @@
+use std::io;
+use std::env;
+use std::mem;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+use libc;
+use serde_json;
+
+
+/// Where local state is kept unless overridden.
+pub const DEFAULT_STATE_DIR: &'static str = "/var/lib/cronwell";
+
+/// The history is rotated once it grows past this size.  One rotated
+/// file is kept so the history never takes more than twice this.
+const MAX_HISTORY_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Resource usage of a finished run.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Rusage {
+    pub user_time: f64,
+    pub system_time: f64,
+    pub max_rss_kb: i64,
+}
+
+/// A single run as stored in the local history.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RunRecord {
+    pub run_id: String,
+    /// The name of the monitor from `MonitorId::name`.  Never the URL as
+    /// that carries the secret and records end up in metrics, spans and
+    /// events.
+    pub monitor: String,
+    pub job: Option<String>,
+    pub command: String,
+    pub start: f64,
+    pub end: f64,
+    pub duration: f64,
+    pub status: i32,
+    pub failed: bool,
+    pub rusage: Option<Rusage>,
+    pub output: Vec<String>,
+}
+
+/// An append-only log of runs stored as JSON lines.
+pub struct History {
+    path: PathBuf,
+    rotated_path: PathBuf,
+    clock_skew_path: PathBuf,
+}
+
+
+fn timeval_to_seconds(tv: &libc::timeval) -> f64 {
+    (tv.tv_sec as f64) + (tv.tv_usec as f64 / 1e06)
+}
+
+/// Returns the resource usage of all children we waited for.
+pub fn get_children_rusage() -> Option<Rusage> {
+    unsafe {
+        let mut usage: libc::rusage = mem::zeroed();
+        if libc::getrusage(libc::RUSAGE_CHILDREN, &mut usage) != 0 {
+            return None;
+        }
+        Some(Rusage {
+            user_time: timeval_to_seconds(&usage.ru_utime),
+            system_time: timeval_to_seconds(&usage.ru_stime),
+            max_rss_kb: usage.ru_maxrss as i64,
+        })
+    }
+}
+
+/// Returns the directory for local state.
+pub fn get_state_dir(value: Option<&str>) -> PathBuf {
+    if let Some(value) = value {
+        return PathBuf::from(value);
+    }
+    match env::var("CRONWELL_STATE_DIR") {
+        Ok(ref val) if !val.is_empty() => PathBuf::from(val),
+        _ => PathBuf::from(DEFAULT_STATE_DIR),
+    }
+}
+
+
+impl RunRecord {
+    /// The name the run is grouped by: the job name if there is one,
+    /// otherwise the monitor.
+    pub fn key(&self) -> &str {
+        self.job.as_ref().map(|x| &x[..]).unwrap_or(&self.monitor)
+    }
+}
+
+impl History {
+    pub fn new(state_dir: &Path) -> History {
+        History {
+            path: state_dir.join("runs.jsonl"),
+            rotated_path: state_dir.join("runs.jsonl.1"),
+            clock_skew_path: state_dir.join("clock_skew"),
+        }
+    }
+
+    /// Returns the clock skew the last run measured against the server.
+    pub fn load_clock_skew(&self) -> Option<f64> {
+        let mut contents = String::new();
+        match File::open(&self.clock_skew_path) {
+            Ok(mut f) => if f.read_to_string(&mut contents).is_err() {
+                return None;
+            },
+            Err(_) => return None,
+        }
+        contents.trim().parse().ok()
+    }
+
+    /// Remembers the measured clock skew for the next run.
+    pub fn save_clock_skew(&self, skew: f64) -> io::Result<()> {
+        if let Some(parent) = self.clock_skew_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut f = File::create(&self.clock_skew_path)?;
+        writeln!(f, "{}", skew)
+    }
+
+    /// Appends a record.  Each record is a single write to a file opened
+    /// in append mode so concurrent runs do not interleave.  If the file
+    /// got too large it is rotated first, replacing the older rotated
+    /// file.
+    pub fn append(&self, record: &RunRecord) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if fs::metadata(&self.path).map(|x| x.len() >= MAX_HISTORY_SIZE).unwrap_or(false) {
+            fs::rename(&self.path, &self.rotated_path)?;
+        }
+        let mut line = serde_json::to_vec(record)
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        line.push(b'\n');
+        let mut f = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        f.write_all(&line)
+    }
+
+    /// Returns all records including the rotated ones, oldest first.
+    /// Lines that cannot be parsed are skipped.
+    pub fn records(&self) -> io::Result<Vec<RunRecord>> {
+        let mut rv = vec![];
+        for path in &[&self.rotated_path, &self.path] {
+            let f = match File::open(path) {
+                Ok(f) => f,
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            for line in BufReader::new(f).lines() {
+                if let Ok(record) = serde_json::from_str(&line?) {
+                    rv.push(record);
+                }
+            }
+        }
+        Ok(rv)
+    }
+
+    /// Returns how many of the most recent runs of a job failed in a row.
+    pub fn consecutive_failures(&self, key: &str) -> io::Result<u32> {
+        Ok(self.records()?.iter().rev()
+            .filter(|record| record.key() == key)
+            .take_while(|record| record.failed)
+            .count() as u32)
+    }
+
+    /// Returns the records matching a job name and start cutoff.
+    pub fn query(&self, job: Option<&str>, since: Option<f64>)
+        -> io::Result<Vec<RunRecord>>
+    {
+        Ok(self.records()?.into_iter().filter(|record| {
+            job.map(|x| record.key() == x).unwrap_or(true) &&
+                since.map(|x| record.start >= x).unwrap_or(true)
+        }).collect())
+    }
+}
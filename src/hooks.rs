@@ -0,0 +1,116 @@
+use std::fs;
+use std::env;
+use std::thread;
+use std::fs::{DirBuilder, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio, ExitStatus};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::os::unix::process::ExitStatusExt;
+use std::time::{Duration, Instant};
+
+use libc;
+use uuid::Uuid;
+
+use error::Error;
+use processtools::{get_unix_exit_status, kill_process_group, set_new_process_group};
+
+
+/// Details about a finished run that are exposed to hooks.
+pub struct HookContext<'a> {
+    pub run_id: &'a str,
+    pub job: Option<&'a str>,
+    pub command: &'a str,
+    pub status: i32,
+    pub failed: bool,
+    pub duration: f64,
+    pub output: &'a [String],
+}
+
+/// The result of running a hook.
+pub enum HookResult {
+    Exited(i32),
+    TimedOut,
+}
+
+
+/// Writes the output for the hook into a new directory only we can
+/// access.  Neither the directory nor the file may exist already so
+/// nobody else can plant a symlink there.  Returns the directory and
+/// the file.
+fn write_output_file(ctx: &HookContext) -> Result<(PathBuf, PathBuf), Error> {
+    let dir = env::temp_dir().join(format!("cronwell-{}", Uuid::new_v4().simple()));
+    DirBuilder::new().mode(0o700).create(&dir)?;
+    let path = dir.join("output.log");
+    let rv = OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path)
+        .and_then(|mut f| {
+            for line in ctx.output {
+                writeln!(f, "{}", line)?;
+            }
+            Ok(())
+        });
+    if let Err(err) = rv {
+        fs::remove_dir_all(&dir).ok();
+        return Err(Error::from(err));
+    }
+    Ok((dir, path))
+}
+
+/// Waits for a process but gives up after the timeout, in which case
+/// the process is killed together with its process group.
+fn wait_with_timeout(pid: libc::pid_t, timeout: Duration) -> HookResult {
+    let started = Instant::now();
+    loop {
+        let mut status = 0;
+        let rv = unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) };
+        if rv == pid {
+            return HookResult::Exited(
+                get_unix_exit_status(ExitStatus::from_raw(status)).unwrap_or(255));
+        } else if rv < 0 {
+            return HookResult::Exited(255);
+        }
+        if started.elapsed() >= timeout {
+            unsafe {
+                kill_process_group(pid, libc::SIGKILL);
+                libc::waitpid(pid, &mut status, 0);
+            }
+            return HookResult::TimedOut;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// Runs a hook command through the shell.
+///
+/// The output of the run is written to a private temporary file for the
+/// hook which is removed again once the hook finished.
+pub fn run_hook(cmd: &str, ctx: &HookContext, timeout: Duration)
+    -> Result<HookResult, Error>
+{
+    let (output_dir, output_file) = write_output_file(ctx)?;
+    let mut command = Command::new("/bin/sh");
+    // the hook runs in its own group so a timeout also ends what it started
+    set_new_process_group(&mut command);
+    // a cronwell started by the hook is not a step of the finished run
+    command.env_remove("CRONWELL_RUN_ID");
+    command.env_remove("CRONWELL_CONTROL_FD");
+    command.env_remove("CRONWELL_ATTEMPT");
+    let child = command
+        .arg("-c")
+        .arg(cmd)
+        .env("CRONWELL_HOOK_RUN_ID", ctx.run_id)
+        .env("CRONWELL_JOB", ctx.job.unwrap_or(""))
+        .env("CRONWELL_COMMAND", ctx.command)
+        .env("CRONWELL_STATUS", ctx.status.to_string())
+        .env("CRONWELL_FAILED", if ctx.failed { "1" } else { "0" })
+        .env("CRONWELL_DURATION", format!("{:.3}", ctx.duration))
+        .env("CRONWELL_OUTPUT_FILE", &output_file)
+        .stdin(Stdio::null())
+        .spawn();
+    let rv = match child {
+        Ok(child) => Ok(wait_with_timeout(child.id() as libc::pid_t, timeout)),
+        Err(err) => Err(Error::from(err)),
+    };
+    fs::remove_dir_all(&output_dir).ok();
+    rv
+}
@@ -6,18 +6,37 @@ extern crate serde_json;
 extern crate base64;
 extern crate clap;
 extern crate curl;
+extern crate flate2;
 extern crate libc;
+extern crate regex;
 extern crate url;
+extern crate uuid;
 
 mod macros;
 
 mod api;
 mod cli;
+mod context;
+mod control;
+mod crontab;
 mod error;
+mod event;
+mod history;
+mod hooks;
+mod metrics;
 mod monitorid;
+mod otel;
 mod processtools;
+mod reporter;
+mod run;
+mod spool;
+mod systemd;
+mod trigger;
 mod utils;
 
+#[cfg(test)]
+mod testutils;
+
 
 fn main() {
     if let Err(ref err) = cli::execute() {
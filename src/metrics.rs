@@ -0,0 +1,186 @@
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::net::{UdpSocket, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+
+use api::{Api, Method, check_url};
+use error::Error;
+use history::{History, RunRecord};
+
+
+/// The metrics of a job after a run.
+pub struct JobMetrics {
+    pub job: String,
+    pub last_start: f64,
+    pub last_success: Option<f64>,
+    pub last_duration: f64,
+    pub last_exit_code: i32,
+    pub last_failed: bool,
+    pub runs_total: u64,
+    pub failures_total: u64,
+}
+
+
+fn escape_label(value: &str) -> String {
+    value.replace("\\", "\\\\").replace("\"", "\\\"").replace("\n", "\\n")
+}
+
+fn sanitize_filename(value: &str) -> String {
+    value.chars()
+        .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect()
+}
+
+fn sanitize_tag(value: &str) -> String {
+    value.chars()
+        .map(|c| if c == ',' || c == '|' || c == '#' || c.is_whitespace() { '_' } else { c })
+        .collect()
+}
+
+/// Sends the metrics of a single run as StatsD packets with DogStatsD
+/// style tags.  Runs are tagged with the job or the monitor name.
+pub fn send_statsd(record: &RunRecord, addr: &str, host: Option<&str>)
+    -> Result<(), Error>
+{
+    let target = match addr.to_socket_addrs()?.next() {
+        Some(target) => target,
+        None => fail!("Could not resolve statsd address {}", addr),
+    };
+    let mut tags = format!("#job:{}", sanitize_tag(record.key()));
+    if let Some(host) = host {
+        tags.push_str(&format!(",host:{}", sanitize_tag(host)));
+    }
+
+    let lines = vec![
+        format!("cronwell.run.duration:{}|ms|{}",
+                (record.duration * 1000.0).round() as u64, tags),
+        format!("cronwell.run.{}:1|c|{}",
+                if record.failed { "failure" } else { "success" }, tags),
+        format!("cronwell.run.exit_code:{}|g|{}", record.status, tags),
+    ];
+
+    let bind_addr = if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = UdpSocket::bind(bind_addr)?;
+    socket.send_to(lines.join("\n").as_bytes(), target)?;
+    Ok(())
+}
+
+
+impl JobMetrics {
+    /// Builds the metrics for a run that was just recorded.  Counters and
+    /// the last success come from the local history.
+    pub fn from_run(record: &RunRecord, history: &History) -> JobMetrics {
+        let mut rv = JobMetrics {
+            job: record.key().to_string(),
+            last_start: record.start,
+            last_success: if record.failed { None } else { Some(record.end) },
+            last_duration: record.duration,
+            last_exit_code: record.status,
+            last_failed: record.failed,
+            runs_total: 1,
+            failures_total: if record.failed { 1 } else { 0 },
+        };
+        if let Ok(records) = history.query(Some(record.key()), None) {
+            // the history already contains the current run
+            if records.iter().any(|x| x.run_id == record.run_id) {
+                rv.runs_total = records.len() as u64;
+                rv.failures_total = records.iter().filter(|x| x.failed).count() as u64;
+                rv.last_success = records.iter().rev()
+                    .find(|x| !x.failed).map(|x| x.end);
+            }
+        }
+        rv
+    }
+
+    /// Renders the metrics in the Prometheus text format.  The `job`
+    /// label is left out for the Pushgateway which adds it from the
+    /// grouping key.
+    pub fn render(&self, with_job_label: bool) -> String {
+        let labels = if with_job_label {
+            format!("{{job=\"{}\"}}", escape_label(&self.job))
+        } else {
+            String::new()
+        };
+        let mut rv = String::new();
+        {
+            let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+                rv.push_str(&format!("# HELP {} {}\n", name, help));
+                rv.push_str(&format!("# TYPE {} {}\n", name, kind));
+                rv.push_str(&format!("{}{} {}\n", name, labels, value));
+            };
+            metric("cronwell_last_start_timestamp_seconds", "gauge",
+                   "When the last run started.", self.last_start.to_string());
+            if let Some(last_success) = self.last_success {
+                metric("cronwell_last_success_timestamp_seconds", "gauge",
+                       "When the last successful run finished.", last_success.to_string());
+            }
+            metric("cronwell_last_duration_seconds", "gauge",
+                   "How long the last run took.", self.last_duration.to_string());
+            metric("cronwell_last_exit_code", "gauge",
+                   "The exit code of the last run.", self.last_exit_code.to_string());
+            metric("cronwell_last_failed", "gauge",
+                   "1 if the last run failed.", (if self.last_failed { 1 } else { 0 }).to_string());
+            metric("cronwell_runs_total", "counter",
+                   "The number of runs.", self.runs_total.to_string());
+            metric("cronwell_failures_total", "counter",
+                   "The number of failed runs.", self.failures_total.to_string());
+        }
+        rv
+    }
+
+    /// Writes the metrics for node_exporter's textfile collector.  The
+    /// file is written next to the target and renamed so the collector
+    /// never sees a partial file.
+    pub fn write_textfile(&self, dir: &Path) -> Result<PathBuf, Error> {
+        let name = format!("cronwell_{}.prom", sanitize_filename(&self.job));
+        let path = dir.join(&name);
+        let tmp_path = dir.join(format!(".{}.tmp", name));
+        {
+            let mut f = File::create(&tmp_path)?;
+            f.write_all(self.render(true).as_bytes())?;
+            f.sync_all()?;
+        }
+        fs::rename(&tmp_path, &path)?;
+        Ok(path)
+    }
+
+    /// Pushes the metrics to a Pushgateway, replacing the previous ones
+    /// of this job.
+    pub fn push(&self, url: &str) -> Result<(), Error> {
+        let url = format!("{}/metrics/job/{}", url.trim_right_matches('/'),
+                          sanitize_filename(&self.job));
+        check_url(&url)?;
+        let api = Api::plain();
+        api.request(Method::Put, &url)?
+            .with_body(self.render(false).into_bytes(), "text/plain; version=0.0.4")?
+            .send()?
+            .to_result()?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    use testutils::run_record;
+    use super::send_statsd;
+
+    #[test]
+    fn test_send_statsd() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let addr = socket.local_addr().unwrap().to_string();
+        send_statsd(&run_record(2), &addr, Some("web 1")).unwrap();
+
+        let mut buf = [0u8; 1024];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(String::from_utf8_lossy(&buf[..len]),
+                   "cronwell.run.duration:1500|ms|#job:nightly-backup,host:web_1\n\
+                    cronwell.run.failure:1|c|#job:nightly-backup,host:web_1\n\
+                    cronwell.run.exit_code:2|g|#job:nightly-backup,host:web_1");
+    }
+}
@@ -0,0 +1,166 @@
+use std::env;
+use std::collections::BTreeMap;
+
+use serde_json;
+use serde_json::Value;
+
+use api::{Api, Method, check_url};
+use error::Error;
+use history::RunRecord;
+use run::RunInfo;
+
+
+/// Where spans go if neither the command line nor the environment
+/// configure a collector.
+pub const DEFAULT_OTLP_ENDPOINT: &'static str = "http://localhost:4318/v1/traces";
+
+// span kind and status codes from the OTLP protobuf definitions
+const SPAN_KIND_INTERNAL: u64 = 1;
+const STATUS_CODE_OK: u64 = 1;
+const STATUS_CODE_ERROR: u64 = 2;
+
+
+fn object(items: Vec<(&str, Value)>) -> Value {
+    let mut rv = BTreeMap::new();
+    for (key, value) in items {
+        rv.insert(key.to_string(), value);
+    }
+    Value::Object(rv)
+}
+
+fn string_value(value: &str) -> Value {
+    object(vec![("stringValue", Value::String(value.to_string()))])
+}
+
+fn int_value(value: i64) -> Value {
+    // 64 bit integers are encoded as strings in OTLP/JSON
+    object(vec![("intValue", Value::String(value.to_string()))])
+}
+
+fn double_value(value: f64) -> Value {
+    object(vec![("doubleValue", Value::F64(value))])
+}
+
+fn attribute(key: &str, value: Value) -> Value {
+    object(vec![
+        ("key", Value::String(key.to_string())),
+        ("value", value),
+    ])
+}
+
+fn to_unix_nanos(timestamp: f64) -> Value {
+    Value::String(((timestamp * 1e9) as u64).to_string())
+}
+
+/// Returns the traces endpoint of the collector.
+///
+/// Follows the OpenTelemetry SDK conventions: the traces specific
+/// variable is used as is, the generic one is a base URL.
+pub fn get_otlp_endpoint(value: Option<&str>) -> String {
+    if let Some(value) = value {
+        return value.to_string();
+    }
+    match env::var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT") {
+        Ok(ref val) if !val.is_empty() => return val.clone(),
+        _ => {}
+    }
+    match env::var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+        Ok(ref val) if !val.is_empty() => {
+            format!("{}/v1/traces", val.trim_right_matches('/'))
+        }
+        _ => DEFAULT_OTLP_ENDPOINT.to_string(),
+    }
+}
+
+/// Builds the OTLP/JSON export request with a single span for a run.
+pub fn make_span_export(run: &RunInfo, record: &RunRecord, host: Option<&str>) -> Value {
+    let mut attributes = vec![
+        attribute("cronwell.run_id", string_value(&record.run_id)),
+        attribute("cronwell.attempt", int_value(run.attempt as i64)),
+        attribute("process.command", string_value(&record.command)),
+        attribute("process.exit_code", int_value(record.status as i64)),
+        attribute("cronwell.duration", double_value(record.duration)),
+    ];
+    if let Some(ref job) = record.job {
+        attributes.push(attribute("cronwell.job", string_value(job)));
+    }
+    if !record.monitor.is_empty() {
+        attributes.push(attribute("cronwell.monitor", string_value(&record.monitor)));
+    }
+    if let Some(ref rusage) = record.rusage {
+        attributes.push(attribute("process.cpu.user_time", double_value(rusage.user_time)));
+        attributes.push(attribute("process.cpu.system_time", double_value(rusage.system_time)));
+        attributes.push(attribute("process.max_rss_kb", int_value(rusage.max_rss_kb)));
+    }
+
+    let mut span = vec![
+        ("traceId", Value::String(run.trace_id.clone())),
+        ("spanId", Value::String(run.span_id.clone())),
+        // the job name, or the monitor name if the run has no job name
+        ("name", Value::String(format!("cron {}", record.key()))),
+        ("kind", Value::U64(SPAN_KIND_INTERNAL)),
+        ("startTimeUnixNano", to_unix_nanos(record.start)),
+        ("endTimeUnixNano", to_unix_nanos(record.end)),
+        ("attributes", Value::Array(attributes)),
+        ("status", object(vec![
+            ("code", Value::U64(if record.failed { STATUS_CODE_ERROR } else { STATUS_CODE_OK })),
+        ])),
+    ];
+    if let Some(ref parent_span_id) = run.parent_span_id {
+        span.push(("parentSpanId", Value::String(parent_span_id.clone())));
+    }
+
+    let mut resource_attributes = vec![
+        attribute("service.name", string_value("cronwell")),
+    ];
+    if let Some(host) = host {
+        resource_attributes.push(attribute("host.name", string_value(host)));
+    }
+
+    object(vec![
+        ("resourceSpans", Value::Array(vec![object(vec![
+            ("resource", object(vec![
+                ("attributes", Value::Array(resource_attributes)),
+            ])),
+            ("scopeSpans", Value::Array(vec![object(vec![
+                ("scope", object(vec![
+                    ("name", Value::String("cronwell".into())),
+                    ("version", Value::String(env!("CARGO_PKG_VERSION").into())),
+                ])),
+                ("spans", Value::Array(vec![object(span)])),
+            ])])),
+        ])])),
+    ])
+}
+
+/// Exports the span of a run to an OTLP/HTTP collector.
+pub fn export_span(endpoint: &str, run: &RunInfo, record: &RunRecord,
+                   host: Option<&str>) -> Result<(), Error> {
+    let body = serde_json::to_vec(&make_span_export(run, record, host))
+        .map_err(|err| format!("could not serialize span: {}", err))?;
+    check_url(endpoint)?;
+    Api::plain().request(Method::Post, endpoint)?
+        .with_body(body, "application/json")?
+        .send()?
+        .to_result()?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use run::RunInfo;
+    use testutils::run_record;
+    use super::make_span_export;
+
+    #[test]
+    fn test_span_names_the_monitor() {
+        let export = make_span_export(&RunInfo::from_env(), &run_record(0), None);
+        let span = export.find("resourceSpans").and_then(|x| x.as_array())
+            .and_then(|x| x[0].find("scopeSpans")).and_then(|x| x.as_array())
+            .and_then(|x| x[0].find("spans")).and_then(|x| x.as_array())
+            .map(|x| x[0].clone()).unwrap();
+        assert_eq!(span.find("name").and_then(|x| x.as_string()),
+                   Some("cron nightly-backup"));
+    }
+}
@@ -2,17 +2,38 @@ use std::io;
 use std::io::{Read, Write, BufRead, BufReader};
 use std::collections::VecDeque;
 use std::collections::vec_deque::IntoIter as VecDequeIntoIter;
+use std::iter::Map;
+use std::fs::File;
 use std::process::{Child, Command, Stdio, ExitStatus};
-use std::os::unix::process::ExitStatusExt;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{channel, Sender, Receiver};
 use std::thread;
+use std::time::{Duration, Instant};
 
+use libc;
+use regex::Regex;
+
+use control::{ControlMessage, parse_control_line};
 use error::Error;
 
 
 pub enum Chunk {
     Stdout(Vec<u8>),
     Stderr(Vec<u8>),
+    Control(ControlMessage),
+}
+
+/// Terminates a process that runs longer than allowed.
+///
+/// The process group of the process is sent `SIGTERM` once the limit is
+/// reached and `SIGKILL` if the process is still running after the kill
+/// timeout.  The process has to be started with `set_new_process_group`
+/// so this also ends everything it started.
+pub struct RuntimeLimit {
+    done: Arc<AtomicBool>,
+    exceeded: Arc<AtomicBool>,
 }
 
 pub struct ProcessIterator {
@@ -21,11 +42,21 @@ pub struct ProcessIterator {
 
 pub struct LineBuffer {
     max_lines: usize,
-    lines: VecDeque<String>,
+    lines: VecDeque<BufferedLine>,
+    fail_patterns: Vec<Regex>,
+    fail_on_stderr: bool,
+    failed: bool,
+}
+
+pub struct BufferedLine {
+    text: String,
+    matched: bool,
 }
 
 
-fn reader_proc(child: &mut Child) -> Receiver<Option<Chunk>> {
+fn reader_proc(child: &mut Child, control: Option<File>)
+    -> Receiver<Option<Chunk>>
+{
     fn read<R, F>(readable: Option<R>, tx: Sender<Option<Chunk>>,
                   wrap: F)
     where
@@ -49,9 +80,27 @@ fn reader_proc(child: &mut Child) -> Receiver<Option<Chunk>> {
         }
     };
 
+    fn read_control(control: File, tx: Sender<Option<Chunk>>) {
+        thread::spawn(move || {
+            for line_rv in BufReader::new(control).lines() {
+                match line_rv {
+                    Ok(line) => {
+                        for msg in parse_control_line(&line) {
+                            let _ = tx.send(Some(Chunk::Control(msg)));
+                        }
+                    }
+                    Err(_) => break,
+                }
+            }
+        });
+    }
+
     let (tx, rx) = channel();
     read(child.stdout.take(), tx.clone(), |l| Chunk::Stdout(l));
     read(child.stderr.take(), tx.clone(), |l| Chunk::Stderr(l));
+    if let Some(control) = control {
+        read_control(control, tx.clone());
+    }
     rx
 }
 
@@ -61,13 +110,15 @@ impl Chunk {
         match *self {
             Chunk::Stdout(ref data) => data,
             Chunk::Stderr(ref data) => data,
+            Chunk::Control(_) => &[],
         }
     }
 
     pub fn echo(&self) -> Result<usize, io::Error> {
         match *self {
             Chunk::Stdout(ref data) => io::stdout().write(data),
-            Chunk::Stderr(ref data) => io::stderr().write(data)
+            Chunk::Stderr(ref data) => io::stderr().write(data),
+            Chunk::Control(_) => Ok(0),
         }
     }
 }
@@ -75,7 +126,15 @@ impl Chunk {
 impl ProcessIterator {
     pub fn new(child: &mut Child) -> ProcessIterator {
         ProcessIterator {
-            rx: reader_proc(child)
+            rx: reader_proc(child, None)
+        }
+    }
+
+    /// Like `new` but also yields the messages sent over the control
+    /// channel of the process.
+    pub fn with_control(child: &mut Child, control: File) -> ProcessIterator {
+        ProcessIterator {
+            rx: reader_proc(child, Some(control))
         }
     }
 }
@@ -92,16 +151,65 @@ impl Iterator for ProcessIterator {
 }
 
 
-pub type LineBufferIntoIter = VecDequeIntoIter<String>;
+pub type LineBufferIntoIter = Map<VecDequeIntoIter<BufferedLine>,
+                                  fn(BufferedLine) -> String>;
+
+impl BufferedLine {
+    /// Renders the line for a failure report.  Lines that triggered a
+    /// failure through an output pattern are marked so they stand out.
+    pub fn render(self) -> String {
+        if self.matched {
+            format!(">>> {}", self.text)
+        } else {
+            self.text
+        }
+    }
+}
 
 impl LineBuffer {
     pub fn new(max_lines: usize) -> LineBuffer {
         LineBuffer {
             max_lines: max_lines,
             lines: VecDeque::new(),
+            fail_patterns: vec![],
+            fail_on_stderr: false,
+            failed: false,
         }
     }
 
+    /// Marks the run as failed if any line matches one of these patterns.
+    pub fn set_fail_patterns(&mut self, patterns: Vec<Regex>) {
+        self.fail_patterns = patterns;
+    }
+
+    /// Marks the run as failed if anything is written to stderr.
+    pub fn set_fail_on_stderr(&mut self, value: bool) {
+        self.fail_on_stderr = value;
+    }
+
+    /// Returns the last `n` lines rendered like in a failure report.
+    pub fn tail(&self, n: usize) -> Vec<String> {
+        let skip = self.lines.len().saturating_sub(n);
+        self.lines.iter().skip(skip).map(|line| BufferedLine {
+            text: line.text.clone(),
+            matched: line.matched,
+        }.render()).collect()
+    }
+
+    /// Returns `true` if the output seen so far should fail the run.
+    pub fn output_failed(&self) -> bool {
+        self.failed
+    }
+
+    fn line_matches(&self, chunk: &Chunk, line: &str) -> bool {
+        if self.fail_on_stderr {
+            if let Chunk::Stderr(_) = *chunk {
+                return true;
+            }
+        }
+        self.fail_patterns.iter().any(|x| x.is_match(line))
+    }
+
     pub fn append_chunk(&mut self, chunk: &Chunk) {
         let mut rdr = BufReader::new(chunk.bytes());
 
@@ -109,7 +217,14 @@ impl LineBuffer {
         // will be fed complete lines which is currently not enforced
         for line_rv in rdr.lines() {
             if let Ok(line) = line_rv {
-                self.lines.push_back(line);
+                let matched = self.line_matches(chunk, &line);
+                if matched {
+                    self.failed = true;
+                }
+                self.lines.push_back(BufferedLine {
+                    text: line,
+                    matched: matched,
+                });
                 if self.lines.len() > self.max_lines {
                     self.lines.pop_front();
                 }
@@ -123,11 +238,104 @@ impl IntoIterator for LineBuffer {
     type IntoIter = LineBufferIntoIter;
 
     fn into_iter(self) -> LineBufferIntoIter {
-        self.lines.into_iter()
+        self.lines.into_iter().map(BufferedLine::render as fn(BufferedLine) -> String)
     }
 }
 
+impl RuntimeLimit {
+    pub fn spawn(child: &Child, max_runtime: Duration,
+                 kill_timeout: Duration) -> RuntimeLimit {
+        let pid = child.id() as libc::pid_t;
+        let done = Arc::new(AtomicBool::new(false));
+        let exceeded = Arc::new(AtomicBool::new(false));
+        let started = Instant::now();
+
+        let thread_done = done.clone();
+        let thread_exceeded = exceeded.clone();
+        thread::spawn(move || {
+            let wait_until = |limit: Duration| {
+                while started.elapsed() < limit {
+                    if thread_done.load(Ordering::SeqCst) {
+                        return false;
+                    }
+                    thread::sleep(Duration::from_millis(100));
+                }
+                !thread_done.load(Ordering::SeqCst)
+            };
+            if !wait_until(max_runtime) {
+                return;
+            }
+            thread_exceeded.store(true, Ordering::SeqCst);
+            kill_process_group(pid, libc::SIGTERM);
+            if wait_until(max_runtime + kill_timeout) {
+                kill_process_group(pid, libc::SIGKILL);
+            }
+        });
+
+        RuntimeLimit {
+            done: done,
+            exceeded: exceeded,
+        }
+    }
+
+    /// Stops watching the process once it was waited for and returns
+    /// `true` if it had to be terminated.
+    pub fn finish(self) -> bool {
+        self.done.store(true, Ordering::SeqCst);
+        self.exceeded.load(Ordering::SeqCst)
+    }
+}
+
+
+/// Starts the command in a process group of its own.  Signals sent to
+/// the group with `kill_process_group` then also reach everything the
+/// command started, like the commands of a shell pipeline.
+pub fn set_new_process_group(command: &mut Command) {
+    unsafe {
+        command.before_exec(|| {
+            if libc::setpgid(0, 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Sends a signal to the process group led by `pid`.
+pub fn kill_process_group(pid: libc::pid_t, signal: libc::c_int) {
+    unsafe { libc::killpg(pid, signal); }
+}
 
 pub fn get_unix_exit_status(status: ExitStatus) -> Option<i32> {
     status.code().or_else(|| status.signal().map(|x| x + 127))
 }
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::process::{Command, Stdio};
+    use std::time::Duration;
+
+    use super::{RuntimeLimit, set_new_process_group};
+
+    #[test]
+    fn test_runtime_limit_kills_the_pipeline() {
+        let mut command = Command::new("/bin/sh");
+        set_new_process_group(&mut command);
+        let mut child = command
+            .arg("-c")
+            .arg("sleep 1000 | cat")
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let limit = RuntimeLimit::spawn(&child, Duration::from_millis(200),
+                                        Duration::from_millis(200));
+
+        // cat holds on to stdout, so this only ends if it was killed too
+        let mut output = vec![];
+        child.stdout.take().unwrap().read_to_end(&mut output).unwrap();
+        child.wait().unwrap();
+        assert!(limit.finish());
+    }
+}
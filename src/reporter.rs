@@ -0,0 +1,450 @@
+use std::io;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use serde_json;
+use serde_json::Value;
+
+use api::{Api, Attachment, Idempotent, Method, check_url, MonitorStatus, RunStart, RunFailure,
+          RunComplete, RunHeartbeat, RunStep};
+use error::Error;
+use monitorid::MonitorId;
+
+
+/// Something that check-ins of a run are reported to.
+///
+/// Reporters are shared with the thread that sends heartbeats.
+pub trait Reporter: Send + Sync {
+    /// Reports the start of a run.  Reporters that know about the state
+    /// of the monitor return it.
+    fn report_start(&self, start: &RunStart) -> Result<Option<MonitorStatus>, Error>;
+    fn report_heartbeat(&self, heartbeat: &RunHeartbeat) -> Result<(), Error>;
+    fn report_complete(&self, complete: &RunComplete) -> Result<(), Error>;
+    fn report_failure(&self, failure: &RunFailure) -> Result<(), Error>;
+
+    /// Reports a nested run as a step of this run.  Reporters without a
+    /// notion of steps ignore it.
+    fn report_step(&self, _step: &RunStep) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Uploads a file for a run.  Reporters that cannot store files
+    /// ignore it.
+    fn report_attachment(&self, _run_id: &str, _attachment: &Attachment)
+        -> Result<(), Error>
+    {
+        Ok(())
+    }
+}
+
+/// Reports to a Sentry monitor.
+pub struct SentryReporter {
+    id: MonitorId,
+}
+
+/// Posts every check-in as JSON to a URL.
+///
+/// Without a template the body is `{"event": ..., "payload": ...}`.  A
+/// template can refer to the event with `{{event}}` and to fields of the
+/// payload with `{{field}}`.  Values are inserted JSON encoded so the
+/// template itself has to be valid JSON around them.
+pub struct WebhookReporter {
+    url: String,
+    template: Option<String>,
+}
+
+/// Pings a Healthchecks.io compatible service.
+///
+/// The URL is the ping URL of a check.  Starts go to `/start`, failures
+/// to `/<exit-code>` (or `/fail` if the process exited cleanly) and
+/// heartbeats to `/log`.  The run ID is passed as `rid` so the service
+/// can pair starts with their completion.  Completions and failures
+/// carry the last lines of output as the body.
+pub struct HealthchecksReporter {
+    url: String,
+}
+
+/// Appends every check-in as a JSON line to a file.
+pub struct FileReporter {
+    path: PathBuf,
+}
+
+/// Discards all check-ins.
+pub struct NullReporter;
+
+/// Sends every check-in to a list of reporters.
+pub struct MultiReporter {
+    reporters: Vec<Box<Reporter>>,
+}
+
+
+fn make_event<S: Serialize>(event: &str, payload: &S) -> Value {
+    let mut rv = BTreeMap::new();
+    rv.insert("event".to_string(), Value::String(event.to_string()));
+    rv.insert("payload".to_string(), serde_json::to_value(payload));
+    Value::Object(rv)
+}
+
+fn render_template(template: &str, event: &str, payload: &Value) -> String {
+    let mut rv = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        rv.push_str(&rest[..start]);
+        let end = match rest[start..].find("}}") {
+            Some(end) => start + end,
+            None => {
+                rest = &rest[start..];
+                break;
+            }
+        };
+        let key = rest[start + 2..end].trim();
+        let value = if key == "event" {
+            Value::String(event.to_string())
+        } else {
+            payload.find(key).cloned().unwrap_or(Value::Null)
+        };
+        rv.push_str(&serde_json::to_string(&value).unwrap_or("null".into()));
+        rest = &rest[end + 2..];
+    }
+    rv.push_str(rest);
+    rv
+}
+
+impl SentryReporter {
+    pub fn new(id: MonitorId) -> SentryReporter {
+        SentryReporter { id: id }
+    }
+}
+
+impl Reporter for SentryReporter {
+    fn report_start(&self, start: &RunStart) -> Result<Option<MonitorStatus>, Error> {
+        Ok(Some(Api::new(&self.id).send_start(start)?))
+    }
+
+    fn report_heartbeat(&self, heartbeat: &RunHeartbeat) -> Result<(), Error> {
+        Api::new(&self.id).send_heartbeat(heartbeat)?;
+        Ok(())
+    }
+
+    fn report_complete(&self, complete: &RunComplete) -> Result<(), Error> {
+        Api::new(&self.id).send_complete(complete)?;
+        Ok(())
+    }
+
+    fn report_failure(&self, failure: &RunFailure) -> Result<(), Error> {
+        Api::new(&self.id).send_failure(failure)?;
+        Ok(())
+    }
+
+    fn report_step(&self, step: &RunStep) -> Result<(), Error> {
+        Api::new(&self.id).send_step(step)?;
+        Ok(())
+    }
+
+    fn report_attachment(&self, run_id: &str, attachment: &Attachment)
+        -> Result<(), Error>
+    {
+        Api::new(&self.id).send_attachment(run_id, attachment)?;
+        Ok(())
+    }
+}
+
+impl WebhookReporter {
+    pub fn new(url: &str, template: Option<String>) -> Result<WebhookReporter, Error> {
+        check_url(url)?;
+        Ok(WebhookReporter {
+            url: url.to_string(),
+            template: template,
+        })
+    }
+
+    fn send<S: Serialize + Idempotent>(&self, event: &str, payload: &S) -> Result<(), Error> {
+        let body = match self.template {
+            Some(ref template) => {
+                render_template(template, event, &serde_json::to_value(payload))
+            }
+            None => serde_json::to_string(&make_event(event, payload))
+                .map_err(|err| format!("could not serialize event: {}", err))?,
+        };
+        let api = Api::plain();
+        let mut req = api.request(Method::Post, &self.url)?;
+        if let Some(key) = payload.idempotency_key() {
+            req = req.with_header("Idempotency-Key", &key)?;
+        }
+        req.with_body(body.into_bytes(), "application/json")?
+            .send()?
+            .to_result()?;
+        Ok(())
+    }
+}
+
+impl Reporter for WebhookReporter {
+    fn report_start(&self, start: &RunStart) -> Result<Option<MonitorStatus>, Error> {
+        self.send("start", start)?;
+        Ok(None)
+    }
+
+    fn report_heartbeat(&self, heartbeat: &RunHeartbeat) -> Result<(), Error> {
+        self.send("heartbeat", heartbeat)
+    }
+
+    fn report_complete(&self, complete: &RunComplete) -> Result<(), Error> {
+        self.send("complete", complete)
+    }
+
+    fn report_failure(&self, failure: &RunFailure) -> Result<(), Error> {
+        self.send("failure", failure)
+    }
+
+    fn report_step(&self, step: &RunStep) -> Result<(), Error> {
+        self.send("step", step)
+    }
+}
+
+impl HealthchecksReporter {
+    pub fn new(url: &str) -> Result<HealthchecksReporter, Error> {
+        let url = url.trim_right_matches('/');
+        check_url(url)?;
+        Ok(HealthchecksReporter {
+            url: url.to_string(),
+        })
+    }
+
+    fn ping<P: Idempotent>(&self, suffix: &str, run_id: Option<&String>, payload: &P,
+                           body: &str) -> Result<(), Error> {
+        let mut url = format!("{}{}", self.url, suffix);
+        if let Some(run_id) = run_id {
+            url.push_str(&format!("?rid={}", run_id));
+        }
+        let api = Api::plain();
+        let mut req = api.request(Method::Post, &url)?;
+        if let Some(key) = payload.idempotency_key() {
+            req = req.with_header("Idempotency-Key", &key)?;
+        }
+        req.with_body(body.as_bytes().to_vec(), "text/plain; charset=utf-8")?
+            .send()?
+            .to_result()?;
+        Ok(())
+    }
+}
+
+impl Reporter for HealthchecksReporter {
+    fn report_start(&self, start: &RunStart) -> Result<Option<MonitorStatus>, Error> {
+        self.ping("/start", start.run_id.as_ref(), start, "")?;
+        Ok(None)
+    }
+
+    fn report_heartbeat(&self, heartbeat: &RunHeartbeat) -> Result<(), Error> {
+        let body = match heartbeat.progress {
+            Some(progress) => format!("progress: {:.0}%", progress * 100.0),
+            None => String::new(),
+        };
+        self.ping("/log", heartbeat.run_id.as_ref(), heartbeat, &body)
+    }
+
+    fn report_complete(&self, complete: &RunComplete) -> Result<(), Error> {
+        self.ping("", complete.run_id.as_ref(), complete,
+                  complete.output.as_ref().map(|x| &x[..]).unwrap_or(""))
+    }
+
+    fn report_failure(&self, failure: &RunFailure) -> Result<(), Error> {
+        let suffix = if failure.status != 0 {
+            format!("/{}", failure.status)
+        } else {
+            "/fail".to_string()
+        };
+        self.ping(&suffix, failure.run_id.as_ref(), failure,
+                  failure.output.as_ref().map(|x| &x[..]).unwrap_or(""))
+    }
+}
+
+impl FileReporter {
+    pub fn new(path: &str) -> FileReporter {
+        FileReporter { path: PathBuf::from(path) }
+    }
+
+    fn write<S: Serialize>(&self, event: &str, payload: &S) -> Result<(), Error> {
+        if let Some(parent) = self.path.parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+        let mut line = serde_json::to_vec(&make_event(event, payload))
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        line.push(b'\n');
+        let mut f = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        f.write_all(&line)?;
+        Ok(())
+    }
+}
+
+impl Reporter for FileReporter {
+    fn report_start(&self, start: &RunStart) -> Result<Option<MonitorStatus>, Error> {
+        self.write("start", start)?;
+        Ok(None)
+    }
+
+    fn report_heartbeat(&self, heartbeat: &RunHeartbeat) -> Result<(), Error> {
+        self.write("heartbeat", heartbeat)
+    }
+
+    fn report_complete(&self, complete: &RunComplete) -> Result<(), Error> {
+        self.write("complete", complete)
+    }
+
+    fn report_failure(&self, failure: &RunFailure) -> Result<(), Error> {
+        self.write("failure", failure)
+    }
+
+    fn report_step(&self, step: &RunStep) -> Result<(), Error> {
+        self.write("step", step)
+    }
+}
+
+impl Reporter for NullReporter {
+    fn report_start(&self, _start: &RunStart) -> Result<Option<MonitorStatus>, Error> {
+        Ok(None)
+    }
+
+    fn report_heartbeat(&self, _heartbeat: &RunHeartbeat) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn report_complete(&self, _complete: &RunComplete) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn report_failure(&self, _failure: &RunFailure) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl MultiReporter {
+    pub fn new(reporters: Vec<Box<Reporter>>) -> MultiReporter {
+        MultiReporter { reporters: reporters }
+    }
+
+    /// Calls every reporter even if an earlier one failed and returns the
+    /// first error.
+    fn fan_out<F>(&self, mut f: F) -> Result<(), Error>
+        where F: FnMut(&Reporter) -> Result<(), Error>
+    {
+        let mut rv = Ok(());
+        for reporter in &self.reporters {
+            if let Err(err) = f(&**reporter) {
+                if rv.is_ok() {
+                    rv = Err(err);
+                }
+            }
+        }
+        rv
+    }
+}
+
+impl Reporter for MultiReporter {
+    /// Returns the monitor status of the first reporter that knows it.
+    /// Once a status is known the errors of the other reporters are only
+    /// printed as warnings so they cannot hide the state of the monitor.
+    fn report_start(&self, start: &RunStart) -> Result<Option<MonitorStatus>, Error> {
+        let mut status = None;
+        let mut errors = vec![];
+        for reporter in &self.reporters {
+            match reporter.report_start(start) {
+                Ok(rv) => {
+                    if status.is_none() {
+                        status = rv;
+                    }
+                }
+                Err(err) => errors.push(err),
+            }
+        }
+        let mut errors = errors.into_iter();
+        let first_error = if status.is_some() { None } else { errors.next() };
+        for err in errors {
+            writeln!(&mut io::stderr(), "warning: could not report start: {}", err).ok();
+        }
+        match first_error {
+            Some(err) => Err(err),
+            None => Ok(status),
+        }
+    }
+
+    fn report_heartbeat(&self, heartbeat: &RunHeartbeat) -> Result<(), Error> {
+        self.fan_out(|x| x.report_heartbeat(heartbeat))
+    }
+
+    fn report_complete(&self, complete: &RunComplete) -> Result<(), Error> {
+        self.fan_out(|x| x.report_complete(complete))
+    }
+
+    fn report_failure(&self, failure: &RunFailure) -> Result<(), Error> {
+        self.fan_out(|x| x.report_failure(failure))
+    }
+
+    fn report_step(&self, step: &RunStep) -> Result<(), Error> {
+        self.fan_out(|x| x.report_step(step))
+    }
+
+    fn report_attachment(&self, run_id: &str, attachment: &Attachment)
+        -> Result<(), Error>
+    {
+        self.fan_out(|x| x.report_attachment(run_id, attachment))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+
+    use api::RunComplete;
+    use testutils::serve;
+    use super::{Reporter, HealthchecksReporter};
+
+    fn complete(output: &str) -> RunComplete {
+        RunComplete {
+            run_id: Some("1234".into()),
+            sequence: 1,
+            timestamp: 0.0,
+            progress: None,
+            tags: None,
+            reported_status: None,
+            level: None,
+            recovered: None,
+            consecutive_failures: None,
+            clock_skew: None,
+            output: Some(output.into()),
+        }
+    }
+
+    #[test]
+    fn test_healthchecks_success_sends_output() {
+        let (url, requests) = serve(vec![(200, "OK")]);
+        let reporter = HealthchecksReporter::new(&format!("{}/ping/abc/", url)).unwrap();
+        reporter.report_complete(&complete("copied 3 files\ndone")).unwrap();
+
+        let req = requests.recv().unwrap();
+        assert_eq!(req.method, "POST");
+        assert_eq!(req.path, "/ping/abc?rid=1234");
+        assert_eq!(req.header("Idempotency-Key"), Some("1234:1"));
+        assert_eq!(req.body_str(), "copied 3 files\ndone");
+    }
+
+    #[test]
+    fn test_output_stays_out_of_json_check_ins() {
+        let body = serde_json::to_string(&complete("copied 3 files")).unwrap();
+        assert!(!body.contains("copied 3 files"));
+    }
+
+    #[test]
+    fn test_healthchecks_error_status() {
+        let (url, requests) = serve(vec![(404, "{}")]);
+        let reporter = HealthchecksReporter::new(&format!("{}/ping/abc", url)).unwrap();
+        assert!(reporter.report_complete(&complete("")).is_err());
+        assert_eq!(requests.recv().unwrap().path, "/ping/abc?rid=1234");
+    }
+}
@@ -0,0 +1,292 @@
+use std::env;
+use std::time;
+use std::cell::Cell;
+use std::os::unix::io::RawFd;
+
+use uuid::Uuid;
+
+use api::{RunStart, RunContext, RunFailure, RunComplete, RunHeartbeat, RunStep};
+use control::ControlState;
+use monitorid::MonitorId;
+use trigger::detect_trigger;
+use utils::{clock_skew, report_timestamp, to_seconds};
+
+
+/// The run of an outer cronwell invocation we are nested in.
+pub struct ParentRun {
+    pub run_id: String,
+    /// The control channel of the outer cronwell, steps go there.  This
+    /// is missing if something between us and the outer run closed it.
+    pub control_fd: Option<RawFd>,
+}
+
+/// Identifies a single run of a monitored job.
+pub struct RunInfo {
+    pub run_id: String,
+    pub attempt: u32,
+    pub trace_id: String,
+    pub span_id: String,
+    pub parent_span_id: Option<String>,
+    pub parent: Option<ParentRun>,
+    sequence: Cell<u32>,
+}
+
+/// Extra information sent along with a completion.
+#[derive(Default, Debug)]
+pub struct CompleteInfo {
+    /// Set to `warning` for failures below the alert threshold.
+    pub level: Option<String>,
+    /// Set if this run ended a series of failures.
+    pub recovered: bool,
+    pub consecutive_failures: Option<u32>,
+}
+
+
+fn get_env(key: &str) -> Option<String> {
+    match env::var(key) {
+        Ok(ref val) if val.is_empty() => None,
+        Ok(val) => Some(val),
+        Err(_) => None,
+    }
+}
+
+/// The run ID alone decides whether we are nested.  It is set for every
+/// outer run, no matter which reporters it uses.
+fn get_parent_run() -> Option<ParentRun> {
+    get_env("CRONWELL_RUN_ID").map(|run_id| ParentRun {
+        run_id: run_id,
+        control_fd: get_env("CRONWELL_CONTROL_FD").and_then(|x| x.parse().ok()),
+    })
+}
+
+/// Returns `true` if we are running below another cronwell.  Such runs
+/// are reported through the outer run and need no monitor of their own.
+pub fn is_nested_run() -> bool {
+    get_parent_run().is_some()
+}
+
+fn is_hex_id(s: &str, len: usize) -> bool {
+    s.len() == len && s.chars().all(|c| c.is_digit(16))
+}
+
+/// Picks up the trace from an incoming W3C `TRACEPARENT` or a
+/// `SENTRY_TRACE` header value so that a run started from a traced
+/// process continues that trace.  Returns the trace ID and the parent
+/// span ID if there is one.
+fn get_incoming_trace() -> Option<(String, Option<String>)> {
+    if let Some(val) = get_env("TRACEPARENT") {
+        let parts: Vec<&str> = val.split('-').collect();
+        if parts.len() >= 3 && is_hex_id(parts[1], 32) && is_hex_id(parts[2], 16) {
+            return Some((parts[1].to_string(), Some(parts[2].to_string())));
+        }
+    }
+    get_env("SENTRY_TRACE").and_then(|val| {
+        let mut parts = val.split('-');
+        let trace_id = parts.next().unwrap_or("");
+        if !is_hex_id(trace_id, 32) {
+            return None;
+        }
+        let span_id = parts.next().and_then(|x| {
+            if is_hex_id(x, 16) { Some(x.to_string()) } else { None }
+        });
+        Some((trace_id.to_string(), span_id))
+    })
+}
+
+pub fn join_lines<I: Iterator<Item=String>>(lines: I) -> String {
+    let mut output = String::new();
+    for (idx, line) in lines.enumerate() {
+        if idx > 0 {
+            output.push('\n');
+        }
+        output.push_str(&line);
+    }
+    output
+}
+
+
+impl RunInfo {
+    /// Creates the run info for this invocation.  If we are running below
+    /// another cronwell the parent run is recorded so that this run can
+    /// attach to it instead of reporting on its own.
+    pub fn from_env() -> RunInfo {
+        let parent = get_parent_run();
+        let attempt = if parent.is_some() {
+            get_env("CRONWELL_ATTEMPT").and_then(|x| x.parse().ok()).unwrap_or(1)
+        } else {
+            1
+        };
+        let mut span_id = Uuid::new_v4().simple().to_string();
+        span_id.truncate(16);
+        let (trace_id, parent_span_id) = get_incoming_trace()
+            .unwrap_or_else(|| (Uuid::new_v4().simple().to_string(), None));
+        RunInfo {
+            run_id: Uuid::new_v4().hyphenated().to_string(),
+            attempt: attempt,
+            trace_id: trace_id,
+            span_id: span_id,
+            parent_span_id: parent_span_id,
+            parent: parent,
+            sequence: Cell::new(0),
+        }
+    }
+
+    /// Returns the sequence number for the next check-in of this run.
+    fn next_sequence(&self) -> u32 {
+        let rv = self.sequence.get();
+        self.sequence.set(rv + 1);
+        rv
+    }
+
+    pub fn is_nested(&self) -> bool {
+        self.parent.is_some()
+    }
+
+    /// The value for a `sentry-trace` header.
+    pub fn sentry_trace(&self) -> String {
+        format!("{}-{}-1", self.trace_id, self.span_id)
+    }
+
+    /// The value for a W3C `traceparent` header.
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-01", self.trace_id, self.span_id)
+    }
+
+    /// The value for a `baggage` header.
+    pub fn baggage(&self) -> String {
+        format!("sentry-trace_id={},sentry-sampled=true", self.trace_id)
+    }
+
+    /// The environment variables to expose to the wrapped process.
+    ///
+    /// Nested runs pass the outer run's variables through unchanged so
+    /// that every level attaches to the outermost run.  The monitor is
+    /// only identified by its name as the child must not learn the
+    /// credentials in the monitor URL.
+    pub fn env_vars(&self, id: Option<&MonitorId>) -> Vec<(&'static str, String)> {
+        if self.is_nested() {
+            return vec![];
+        }
+        let mut rv = vec![
+            ("CRONWELL_RUN_ID", self.run_id.clone()),
+            ("CRONWELL_ATTEMPT", self.attempt.to_string()),
+            ("SENTRY_TRACE", self.sentry_trace()),
+            ("SENTRY_BAGGAGE", self.baggage()),
+            ("TRACEPARENT", self.traceparent()),
+        ];
+        if let Some(id) = id {
+            rv.push(("CRONWELL_MONITOR_NAME", id.name()));
+        }
+        rv
+    }
+
+    pub fn start_payload(&self, job: Option<&str>, cmd: &str, args: &[&str],
+                         context: RunContext) -> RunStart {
+        let trigger = detect_trigger();
+        RunStart {
+            run_id: Some(self.run_id.clone()),
+            sequence: self.next_sequence(),
+            job: job.map(|x| x.to_string()),
+            timestamp: Some(report_timestamp()),
+            command: Some(cmd.to_string()),
+            args: Some(args.iter().map(|x| x.to_string()).collect()),
+            from_cron: Some(trigger.is_cron()),
+            trigger: Some(trigger.to_string()),
+            context: Some(context),
+        }
+    }
+
+    pub fn heartbeat_payload(&self, control: &ControlState) -> RunHeartbeat {
+        RunHeartbeat {
+            run_id: Some(self.run_id.clone()),
+            sequence: self.next_sequence(),
+            timestamp: report_timestamp(),
+            progress: control.progress,
+            tags: control.tags(),
+            clock_skew: clock_skew(),
+        }
+    }
+
+    pub fn failure_payload<I>(&self, lines: I, status: i32,
+                              control: &ControlState) -> RunFailure
+    where
+        I: Iterator<Item=String>
+    {
+        RunFailure {
+            run_id: Some(self.run_id.clone()),
+            sequence: self.next_sequence(),
+            status: status,
+            timestamp: report_timestamp(),
+            output: Some(join_lines(lines)),
+            progress: control.progress,
+            tags: control.tags(),
+            reported_status: control.status.clone(),
+            event_id: None,
+            clock_skew: clock_skew(),
+        }
+    }
+
+    pub fn complete_payload<I>(&self, lines: I, control: &ControlState,
+                               info: &CompleteInfo) -> RunComplete
+    where
+        I: Iterator<Item=String>
+    {
+        RunComplete {
+            run_id: Some(self.run_id.clone()),
+            sequence: self.next_sequence(),
+            timestamp: report_timestamp(),
+            progress: control.progress,
+            tags: control.tags(),
+            reported_status: control.status.clone(),
+            level: info.level.clone(),
+            recovered: if info.recovered { Some(true) } else { None },
+            consecutive_failures: info.consecutive_failures,
+            clock_skew: clock_skew(),
+            output: Some(join_lines(lines)),
+        }
+    }
+
+    /// Builds the report of a nested run for the outer run.  The output
+    /// is only included for failed steps.
+    pub fn step_payload<I>(&self, cmd: &str, args: &[&str], lines: I, status: i32,
+                           failed: bool, duration: time::Duration) -> Option<RunStep>
+    where
+        I: Iterator<Item=String>
+    {
+        self.parent.as_ref().map(|parent| RunStep {
+            run_id: parent.run_id.clone(),
+            step_id: self.run_id.clone(),
+            command: cmd.to_string(),
+            args: args.iter().map(|x| x.to_string()).collect(),
+            status: status,
+            timestamp: report_timestamp(),
+            duration: to_seconds(duration),
+            output: if failed { Some(join_lines(lines)) } else { None },
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use api::Idempotent;
+    use context::collect_context;
+    use control::ControlState;
+    use super::{RunInfo, CompleteInfo};
+
+    #[test]
+    fn test_sequence_numbers() {
+        let run = RunInfo::from_env();
+        let control = ControlState::default();
+        let start = run.start_payload(None, "backup.sh", &[], collect_context(&[]));
+        let heartbeat = run.heartbeat_payload(&control);
+        let complete = run.complete_payload(vec![].into_iter(), &control,
+                                            &CompleteInfo::default());
+        assert_eq!((start.sequence, heartbeat.sequence, complete.sequence), (0, 1, 2));
+
+        // every check-in of a run gets a key of its own
+        assert_eq!(start.idempotency_key(), Some(format!("{}:0", run.run_id)));
+        assert_eq!(heartbeat.idempotency_key(), Some(format!("{}:1", run.run_id)));
+        assert_eq!(complete.idempotency_key(), Some(format!("{}:2", run.run_id)));
+    }
+}
@@ -0,0 +1,132 @@
+use std::io;
+use std::fs;
+use std::time;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+
+use api::Attachment;
+use error::Error;
+use processtools::Chunk;
+
+
+/// How much output is spooled unless configured otherwise.
+pub const DEFAULT_LOG_MAX_SIZE: u64 = 10 * 1024 * 1024;
+
+/// How many days compressed logs are kept unless configured otherwise.
+pub const DEFAULT_LOG_RETENTION_DAYS: u64 = 7;
+
+/// Writes the complete output of a run to disk.
+///
+/// Output past the size cap is dropped and a note is appended when the
+/// spool is finished so readers know the log is incomplete.
+pub struct LogSpool {
+    path: PathBuf,
+    file: File,
+    written: u64,
+    max_size: u64,
+    truncated: bool,
+}
+
+
+/// Returns the directory logs are spooled to.
+pub fn get_spool_dir(value: Option<&str>, state_dir: &Path) -> PathBuf {
+    match value {
+        Some(value) => PathBuf::from(value),
+        None => state_dir.join("logs"),
+    }
+}
+
+/// Output can contain secrets so spooled logs are only readable by us.
+fn create_private(path: &Path) -> io::Result<File> {
+    OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)
+}
+
+/// Removes compressed logs that were written longer than the retention
+/// period before the current run started.  The log of the current run
+/// is always kept, as is everything written while it ran, like the logs
+/// of concurrent runs.  With a retention of 0 only the logs of runs
+/// that finished before this one started are removed.
+pub fn cleanup_logs(dir: &Path, retention_days: u64, current: &Path,
+                    started: time::SystemTime) -> Result<(), Error> {
+    let max_age = time::Duration::from_secs(retention_days * 24 * 60 * 60);
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if !path.to_string_lossy().ends_with(".log.gz") || path == current {
+            continue;
+        }
+        let modified = entry.metadata()?.modified()?;
+        match started.duration_since(modified) {
+            Ok(age) if age >= max_age => { fs::remove_file(&path).ok(); }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Reads a compressed log so it can be uploaded.
+pub fn load_attachment(path: &Path) -> Result<Attachment, Error> {
+    let mut data = vec![];
+    File::open(path)?.read_to_end(&mut data)?;
+    Ok(Attachment {
+        filename: path.file_name()
+            .map(|x| x.to_string_lossy().into_owned())
+            .unwrap_or("output.log.gz".into()),
+        content_type: "application/gzip".into(),
+        data: data,
+    })
+}
+
+
+impl LogSpool {
+    pub fn create(dir: &Path, run_id: &str, max_size: u64) -> Result<LogSpool, Error> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(format!("{}.log", run_id));
+        Ok(LogSpool {
+            file: create_private(&path)?,
+            path: path,
+            written: 0,
+            max_size: max_size,
+            truncated: false,
+        })
+    }
+
+    pub fn write_chunk(&mut self, chunk: &Chunk) -> Result<(), Error> {
+        let data = chunk.bytes();
+        let left = self.max_size.saturating_sub(self.written);
+        if (data.len() as u64) > left {
+            self.truncated = true;
+        }
+        let data = &data[..::std::cmp::min(left, data.len() as u64) as usize];
+        if !data.is_empty() {
+            self.file.write_all(data)?;
+            self.written += data.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Compresses the spooled output next to the raw log, which is
+    /// removed afterwards, and returns the path of the compressed log.
+    pub fn finish(mut self) -> Result<PathBuf, Error> {
+        if self.truncated {
+            writeln!(self.file, "\n[cronwell: output truncated after {} bytes]",
+                     self.written)?;
+        }
+        self.file.sync_all()?;
+        drop(self.file);
+
+        let gz_path = PathBuf::from(format!("{}.gz", self.path.display()));
+        {
+            let mut encoder = GzEncoder::new(create_private(&gz_path)?, Compression::Default);
+            io::copy(&mut File::open(&self.path)?, &mut encoder)?;
+            encoder.finish()?;
+        }
+        fs::remove_file(&self.path)?;
+        Ok(gz_path)
+    }
+}
@@ -0,0 +1,214 @@
+use std::env;
+use std::thread;
+use std::time::Duration;
+use std::os::unix::net::UnixDatagram;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use libc::getpid;
+
+
+/// Where environment files with monitor tokens go unless configured
+/// otherwise.
+pub const DEFAULT_ENVIRONMENT_DIR: &'static str = "/etc/cronwell";
+
+/// Describes a job to generate a systemd service and timer for.
+pub struct UnitSpec {
+    pub job: String,
+    pub schedule: String,
+    pub command: Vec<String>,
+    pub cronwell: String,
+    pub monitor_token: Option<String>,
+    /// The token is secret so it goes into this file rather than into
+    /// the unit, which everybody can read.
+    pub environment_file: Option<String>,
+    pub watchdog_sec: Option<u64>,
+}
+
+/// Sends notifications to systemd for units of `Type=notify`.
+pub struct Notifier {
+    socket_path: String,
+}
+
+/// Keeps the systemd watchdog happy while the job makes progress.
+///
+/// A ping is only sent if the job wrote output or a control message
+/// since the last one, so a job that hangs silently for longer than
+/// `WatchdogSec=` gets restarted by systemd.  Once the job exited the
+/// pings continue unconditionally while the run is reported.
+pub struct Watchdog {
+    progress: Arc<AtomicBool>,
+    exited: Arc<AtomicBool>,
+}
+
+
+/// Escapes a string for use in a unit name the way `systemd-escape`
+/// does: `/` becomes `-` and everything outside of `[A-Za-z0-9:_.]`, as
+/// well as a leading dot, is written as `\xNN`.
+pub fn escape_unit_name(s: &str) -> String {
+    let mut rv = String::new();
+    for (idx, byte) in s.bytes().enumerate() {
+        match byte {
+            b'/' => rv.push('-'),
+            b'.' if idx == 0 => rv.push_str("\\x2e"),
+            b'a'...b'z' | b'A'...b'Z' | b'0'...b'9' | b':' | b'_' | b'.' => {
+                rv.push(byte as char);
+            }
+            _ => rv.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    rv
+}
+
+/// Quotes a single word for use in an `ExecStart=` line.
+fn quote_exec_arg(arg: &str) -> String {
+    // systemd expands specifiers and variables in command lines
+    let escaped = arg.replace("%", "%%").replace("$", "$$");
+    if !escaped.is_empty() && !escaped.contains(|c: char| {
+        c.is_whitespace() || c == '"' || c == '\'' || c == '\\' || c == ';'
+    }) {
+        return escaped;
+    }
+    let mut rv = String::from("\"");
+    for c in escaped.chars() {
+        match c {
+            '"' | '\\' => { rv.push('\\'); rv.push(c); }
+            _ => rv.push(c),
+        }
+    }
+    rv.push('"');
+    rv
+}
+
+impl UnitSpec {
+    fn unit_stem(&self) -> String {
+        format!("cronwell-{}", escape_unit_name(&self.job))
+    }
+
+    pub fn service_name(&self) -> String {
+        format!("{}.service", self.unit_stem())
+    }
+
+    pub fn timer_name(&self) -> String {
+        format!("{}.timer", self.unit_stem())
+    }
+
+    /// The environment file the service reads its monitor token from.
+    pub fn environment_path(&self) -> String {
+        match self.environment_file {
+            Some(ref path) => path.clone(),
+            None => format!("{}/{}.env", DEFAULT_ENVIRONMENT_DIR, self.unit_stem()),
+        }
+    }
+
+    /// The contents of the environment file if there is a token.
+    pub fn generate_environment(&self) -> Option<String> {
+        self.monitor_token.as_ref().map(|token| format!("CRONWELL_MONITOR={}\n", token))
+    }
+
+    pub fn generate_service(&self) -> String {
+        let mut exec = vec![quote_exec_arg(&self.cronwell),
+                            "--job".to_string(), quote_exec_arg(&self.job)];
+        exec.extend(self.command.iter().map(|x| quote_exec_arg(x)));
+
+        let mut rv = String::new();
+        rv.push_str("[Unit]\n");
+        rv.push_str(&format!("Description=cronwell job {}\n", self.job));
+        rv.push_str("\n[Service]\n");
+        rv.push_str("Type=notify\n");
+        rv.push_str("NotifyAccess=main\n");
+        if self.monitor_token.is_some() {
+            rv.push_str(&format!("EnvironmentFile={}\n", self.environment_path()));
+        }
+        if let Some(watchdog_sec) = self.watchdog_sec {
+            rv.push_str(&format!("WatchdogSec={}\n", watchdog_sec));
+        }
+        rv.push_str(&format!("ExecStart={}\n", exec.join(" ")));
+        rv
+    }
+
+    pub fn generate_timer(&self) -> String {
+        let mut rv = String::new();
+        rv.push_str("[Unit]\n");
+        rv.push_str(&format!("Description=Timer for cronwell job {}\n", self.job));
+        rv.push_str("\n[Timer]\n");
+        rv.push_str(&format!("OnCalendar={}\n", self.schedule));
+        rv.push_str("Persistent=true\n");
+        rv.push_str(&format!("Unit={}\n", self.service_name()));
+        rv.push_str("\n[Install]\n");
+        rv.push_str("WantedBy=timers.target\n");
+        rv
+    }
+}
+
+impl Notifier {
+    /// Returns a notifier if we are running in a unit that expects
+    /// notifications.  Abstract namespace sockets are not supported.
+    pub fn from_env() -> Option<Notifier> {
+        match env::var("NOTIFY_SOCKET") {
+            Ok(ref path) if path.starts_with('/') => Some(Notifier {
+                socket_path: path.clone(),
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn notify(&self, state: &str) {
+        if let Ok(sock) = UnixDatagram::unbound() {
+            sock.send_to(state.as_bytes(), &self.socket_path).ok();
+        }
+    }
+
+    pub fn ready(&self) {
+        self.notify("READY=1");
+    }
+
+    pub fn status(&self, status: &str) {
+        self.notify(&format!("STATUS={}", status.replace("\n", " ")));
+    }
+
+    /// Starts a background thread that sends watchdog pings at half the
+    /// interval systemd asked for.  Returns `None` if the watchdog is not
+    /// enabled for us.
+    pub fn spawn_watchdog(&self) -> Option<Watchdog> {
+        let usec: u64 = match env::var("WATCHDOG_USEC").ok().and_then(|x| x.parse().ok()) {
+            Some(usec) if usec > 0 => usec,
+            _ => return None,
+        };
+        if let Some(pid) = env::var("WATCHDOG_PID").ok().and_then(|x| x.parse::<i32>().ok()) {
+            if pid != unsafe { getpid() } {
+                return None;
+            }
+        }
+        let watchdog = Watchdog {
+            progress: Arc::new(AtomicBool::new(false)),
+            exited: Arc::new(AtomicBool::new(false)),
+        };
+        let progress = watchdog.progress.clone();
+        let exited = watchdog.exited.clone();
+        let notifier = Notifier { socket_path: self.socket_path.clone() };
+        let interval = Duration::from_millis(usec / 2000);
+        thread::spawn(move || {
+            notifier.notify("WATCHDOG=1");
+            loop {
+                thread::sleep(interval);
+                if progress.swap(false, Ordering::SeqCst) || exited.load(Ordering::SeqCst) {
+                    notifier.notify("WATCHDOG=1");
+                }
+            }
+        });
+        Some(watchdog)
+    }
+}
+
+impl Watchdog {
+    /// Records that the job is still doing something.
+    pub fn progress(&self) {
+        self.progress.store(true, Ordering::SeqCst);
+    }
+
+    /// Records that the job exited and only reporting is left to do.
+    pub fn job_exited(&self) {
+        self.exited.store(true, Ordering::SeqCst);
+    }
+}
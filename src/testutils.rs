@@ -0,0 +1,104 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+
+use uuid::Uuid;
+
+use history::RunRecord;
+
+
+/// A run of `backup.sh` for the `nightly-backup` monitor that took 1.5
+/// seconds and exited with the given status.
+pub fn run_record(status: i32) -> RunRecord {
+    RunRecord {
+        run_id: Uuid::new_v4().hyphenated().to_string(),
+        monitor: "nightly-backup".into(),
+        job: None,
+        command: "backup.sh".into(),
+        start: 100.0,
+        end: 101.5,
+        duration: 1.5,
+        status: status,
+        failed: status != 0,
+        rusage: None,
+        output: vec![],
+    }
+}
+
+/// A request received by the HTTP stand-in.
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        let name = name.to_lowercase();
+        self.headers.iter().find(|x| x.0 == name).map(|x| &x.1[..])
+    }
+
+    pub fn body_str(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+/// Starts a minimal HTTP server on a local port that answers one request
+/// per given status and JSON body.  Returns the base URL and the
+/// requests it received in order.
+pub fn serve(responses: Vec<(u32, &'static str)>) -> (String, Receiver<Request>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        for (status, response) in responses {
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(_) => return,
+            };
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let (method, path) = {
+                let mut parts = line.split_whitespace();
+                (parts.next().unwrap_or("").to_string(),
+                 parts.next().unwrap_or("").to_string())
+            };
+            let mut headers = vec![];
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_right();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some(idx) = line.find(':') {
+                    headers.push((line[..idx].trim().to_lowercase(),
+                                  line[idx + 1..].trim().to_string()));
+                }
+            }
+            let len = headers.iter()
+                .find(|x| x.0 == "content-length")
+                .and_then(|x| x.1.parse().ok())
+                .unwrap_or(0);
+            let mut body = vec![0; len];
+            reader.read_exact(&mut body).unwrap();
+
+            let mut stream = reader.into_inner();
+            write!(stream, "HTTP/1.1 {} Stand-in\r\n\
+                            Content-Type: application/json\r\n\
+                            Content-Length: {}\r\n\
+                            Connection: close\r\n\r\n{}",
+                   status, response.len(), response).unwrap();
+            tx.send(Request {
+                method: method,
+                path: path,
+                headers: headers,
+                body: body,
+            }).ok();
+        }
+    });
+    (url, rx)
+}
@@ -0,0 +1,209 @@
+use std::env;
+use std::fmt;
+use std::path::PathBuf;
+
+use libc::{getppid, isatty, STDIN_FILENO};
+
+
+/// The maximum number of ancestors we look at before giving up.
+const MAX_ANCESTORS: usize = 32;
+
+/// Process names of the cron daemons we know about.
+const CRON_DAEMONS: &'static [&'static str] = &[
+    "cron", "crond", "fcron", "anacron", "cronie", "dcron", "bcron-sched",
+];
+
+/// Where systemd looks for system units.
+const SYSTEMD_UNIT_DIRS: &'static [&'static str] = &[
+    "/etc/systemd/system", "/run/systemd/system",
+    "/usr/lib/systemd/system", "/lib/systemd/system",
+];
+
+/// Variables pointing at the Job or CronJob a pod belongs to.  Kubernetes
+/// does not set these itself, they are exposed through the downward API.
+const KUBERNETES_JOB_VARS: &'static [&'static str] = &[
+    "CRONJOB_NAME", "JOB_NAME", "JOB_COMPLETION_INDEX",
+];
+
+/// What caused the current run.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Trigger {
+    /// Started by a cron daemon.  Holds the name of the daemon.
+    Cron(String),
+    /// Started by a systemd unit, usually through a timer.
+    SystemdTimer,
+    /// Started from a Kubernetes CronJob.
+    Kubernetes,
+    /// Started by hand from a terminal.
+    Interactive,
+    /// Started by something else without a terminal.
+    Manual,
+}
+
+
+#[cfg(target_os="linux")]
+fn get_process_name(pid: i32) -> Option<String> {
+    use std::fs::{File, read_link};
+    use std::io::Read;
+
+    // comm is readable for processes of other users unlike exe, which
+    // matters as the cron daemon runs as root and the job usually not.
+    let mut comm = String::new();
+    if File::open(format!("/proc/{}/comm", pid))
+        .and_then(|mut f| f.read_to_string(&mut comm)).is_ok() {
+        return Some(comm.trim().to_string());
+    }
+    if let Ok(path) = read_link(format!("/proc/{}/exe", pid)) {
+        path.file_name().map(|x| x.to_string_lossy().into_owned())
+    } else {
+        None
+    }
+}
+
+#[cfg(target_os="macos")]
+fn get_process_name(pid: i32) -> Option<String> {
+    use std::mem::transmute;
+    use std::ffi::CStr;
+    use std::path::Path;
+    use libc::{c_int, size_t};
+    extern "C" {
+        fn proc_pidpath(pid: i32, buf: *mut u8, bufsize: size_t) -> c_int;
+    }
+
+    unsafe {
+        let pathbuf = [0u8; 4096];
+        if proc_pidpath(pid, transmute(pathbuf.as_ptr()), pathbuf.len() as size_t) < 0 {
+            return None;
+        }
+        match CStr::from_ptr(transmute(pathbuf.as_ptr())).to_str() {
+            Ok(x) => Path::new(x).file_name().map(|x| x.to_string_lossy().into_owned()),
+            Err(_) => None
+        }
+    }
+}
+
+#[cfg(target_os="linux")]
+fn get_parent_pid(pid: i32) -> Option<i32> {
+    use std::fs::File;
+    use std::io::Read;
+
+    let mut stat = String::new();
+    if File::open(format!("/proc/{}/stat", pid))
+        .and_then(|mut f| f.read_to_string(&mut stat)).is_err() {
+        return None;
+    }
+    // the process name is in parentheses and can contain anything, so
+    // the fields are counted from the last closing parenthesis.
+    stat.rfind(')')
+        .and_then(|idx| stat[idx + 1..].split_whitespace().nth(1))
+        .and_then(|x| x.parse().ok())
+}
+
+#[cfg(target_os="macos")]
+fn get_parent_pid(_pid: i32) -> Option<i32> {
+    // we only look at the direct parent on OS X
+    None
+}
+
+/// Returns the names of all ancestor processes, closest first.
+fn get_ancestor_names() -> Vec<String> {
+    let mut rv = vec![];
+    let mut pid = unsafe { getppid() };
+    while pid > 1 && rv.len() < MAX_ANCESTORS {
+        if let Some(name) = get_process_name(pid) {
+            rv.push(name);
+        }
+        pid = match get_parent_pid(pid) {
+            Some(ppid) => ppid,
+            None => break,
+        };
+    }
+    rv
+}
+
+fn has_env(key: &str) -> bool {
+    env::var(key).map(|x| !x.is_empty()).unwrap_or(false)
+}
+
+/// Returns the name of the systemd unit we are running in from our
+/// cgroup, e.g. `backup.service`.
+fn get_systemd_unit() -> Option<String> {
+    use std::fs::File;
+    use std::io::Read;
+
+    let mut cgroup = String::new();
+    if File::open("/proc/self/cgroup")
+        .and_then(|mut f| f.read_to_string(&mut cgroup)).is_err() {
+        return None;
+    }
+    cgroup.lines()
+        .filter_map(|line| line.rsplit('/').next())
+        .find(|name| name.ends_with(".service"))
+        .map(|name| name.to_string())
+}
+
+/// Returns `true` if a timer of the same name exists for our unit, which
+/// is how timers are set up unless they name another unit.
+fn is_started_by_timer() -> bool {
+    if !has_env("INVOCATION_ID") {
+        return false;
+    }
+    let unit = match get_systemd_unit() {
+        Some(unit) => unit,
+        None => return false,
+    };
+    let timer = format!("{}.timer", &unit[..unit.len() - ".service".len()]);
+    let mut dirs: Vec<PathBuf> = SYSTEMD_UNIT_DIRS.iter().map(PathBuf::from).collect();
+    if let Some(home) = env::home_dir() {
+        dirs.push(home.join(".config/systemd/user"));
+    }
+    dirs.iter().any(|dir| dir.join(&timer).is_file())
+}
+
+
+impl Trigger {
+    pub fn is_cron(&self) -> bool {
+        match *self {
+            Trigger::Cron(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Trigger::Cron(ref flavor) => write!(f, "cron:{}", flavor),
+            Trigger::SystemdTimer => write!(f, "systemd-timer"),
+            Trigger::Kubernetes => write!(f, "kubernetes"),
+            Trigger::Interactive => write!(f, "interactive"),
+            Trigger::Manual => write!(f, "manual"),
+        }
+    }
+}
+
+/// Figures out what started the current process.
+///
+/// A terminal always means someone started us by hand.  Cron daemons
+/// are found by walking up the process tree as jobs are usually started
+/// through an intermediate shell.  Every service and pod looks alike
+/// from the environment, so systemd and Kubernetes only count if there
+/// is evidence of a timer or a job.
+pub fn detect_trigger() -> Trigger {
+    if unsafe { isatty(STDIN_FILENO) } == 1 {
+        return Trigger::Interactive;
+    }
+    for name in get_ancestor_names() {
+        if CRON_DAEMONS.contains(&&name[..]) {
+            return Trigger::Cron(name);
+        }
+    }
+    if is_started_by_timer() {
+        return Trigger::SystemdTimer;
+    }
+    if has_env("KUBERNETES_SERVICE_HOST") &&
+       KUBERNETES_JOB_VARS.iter().any(|x| has_env(x)) {
+        return Trigger::Kubernetes;
+    }
+    Trigger::Manual
+}
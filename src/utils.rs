@@ -1,54 +1,153 @@
-use std::path::Path;
 use std::time;
+use std::sync::atomic::{AtomicBool, AtomicIsize, Ordering, ATOMIC_BOOL_INIT,
+                        ATOMIC_ISIZE_INIT};
 
-use libc::getppid;
 
+// the clock skew measured against the server in milliseconds
+static CLOCK_SKEW_MS: AtomicIsize = ATOMIC_ISIZE_INIT;
+static CLOCK_SKEW_KNOWN: AtomicBool = ATOMIC_BOOL_INIT;
+// the correction applied to reported timestamps in milliseconds
+static CLOCK_CORRECTION_MS: AtomicIsize = ATOMIC_ISIZE_INIT;
 
-#[cfg(target_os="linux")]
-fn get_process_name(pid: i32) -> Option<String> {
-    use std::fs::read_link;
 
-    if let Ok(path) = read_link(format!("/proc/{}/exe", pid)) {
-        path.file_name().map(|x| x.to_string_lossy().into_owned())
+pub fn to_seconds(duration: time::Duration) -> f64 {
+    (duration.as_secs() as f64) + (duration.subsec_nanos() as f64 / 1e09)
+}
+
+pub fn to_timestamp(tm: time::SystemTime) -> f64 {
+    to_seconds(tm.duration_since(time::UNIX_EPOCH).unwrap())
+}
+
+/// Records how far the server clock is ahead of ours.
+pub fn set_clock_skew(secs: f64) {
+    CLOCK_SKEW_MS.store((secs * 1000.0) as isize, Ordering::SeqCst);
+    CLOCK_SKEW_KNOWN.store(true, Ordering::SeqCst);
+}
+
+/// Returns the last measured clock skew in seconds.
+pub fn clock_skew() -> Option<f64> {
+    if CLOCK_SKEW_KNOWN.load(Ordering::SeqCst) {
+        Some(CLOCK_SKEW_MS.load(Ordering::SeqCst) as f64 / 1000.0)
     } else {
         None
     }
 }
 
-#[cfg(target_os="macos")]
-fn get_process_name(pid: i32) -> Option<String> {
-    use std::mem::transmute;
-    use std::ffi::CStr;
-    use libc::{c_int, size_t};
-    extern "C" {
-        fn proc_pidpath(pid: i32, buf: *mut u8, bufsize: size_t) -> c_int;
-    }
+/// Corrects reported timestamps by a clock skew measured earlier.
+///
+/// The correction stays the same for the whole run, even if the skew
+/// measured during the run differs, so that every check-in of a run is
+/// on the same clock.
+pub fn set_clock_correction(secs: f64) {
+    CLOCK_CORRECTION_MS.store((secs * 1000.0) as isize, Ordering::SeqCst);
+}
+
+/// The current time for reports sent to a server.  This is the local
+/// time adjusted by the clock correction, if any.
+pub fn report_timestamp() -> f64 {
+    let now = to_timestamp(time::SystemTime::now());
+    now + CLOCK_CORRECTION_MS.load(Ordering::SeqCst) as f64 / 1000.0
+}
+
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let yoe = year - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
 
-    unsafe {
-        let pathbuf = [0u8; 4096];
-        if proc_pidpath(pid, transmute(pathbuf.as_ptr()), pathbuf.len() as size_t) < 0 {
-            return None;
-        }
-        match CStr::from_ptr(transmute(pathbuf.as_ptr())).to_str() {
-            Ok(x) => Path::new(x).file_name().map(|x| x.to_string_lossy().into_owned()),
-            Err(_) => None
-        }
+/// Parses an HTTP date (`Sun, 06 Nov 1994 08:49:37 GMT`) into a unix
+/// timestamp.
+pub fn parse_http_date(value: &str) -> Option<f64> {
+    let parts: Vec<&str> = value.split_whitespace().collect();
+    if parts.len() != 6 || parts[5] != "GMT" {
+        return None;
+    }
+    let month = match ["Jan", "Feb", "Mar", "Apr", "May", "Jun",
+                       "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"]
+            .iter().position(|x| *x == parts[2]) {
+        Some(idx) => idx as i64 + 1,
+        None => return None,
+    };
+    let day: i64 = match parts[1].parse() { Ok(x) => x, Err(_) => return None };
+    let year: i64 = match parts[3].parse() { Ok(x) => x, Err(_) => return None };
+    let hms: Vec<i64> = match parts[4].split(':').map(|x| x.parse::<i64>()).collect() {
+        Ok(hms) => hms,
+        Err(_) => return None,
+    };
+    if hms.len() != 3 {
+        return None;
     }
+    let days = days_from_civil(year, month, day);
+    Some((days * 86400 + hms[0] * 3600 + hms[1] * 60 + hms[2]) as f64)
 }
 
+/// Formats a unix timestamp as UTC date and time.
+pub fn format_timestamp(ts: f64) -> String {
+    let secs = ts as i64;
+    let days = if secs >= 0 { secs / 86400 } else { (secs - 86399) / 86400 };
+    let rem = secs - days * 86400;
 
-pub fn run_from_cron() -> bool {
-    let parent_pid = unsafe { getppid() };
-    if parent_pid < 0 {
-        false
-    } else if let Some(exe) = get_process_name(parent_pid) {
-        exe == "cron"
-    } else {
-        false
+    // civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day,
+            rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+/// Parses a point in time given either as unix timestamp or as an age
+/// relative to `now` (`30m`, `12h`, `7d`).
+pub fn parse_since(value: &str, now: f64) -> Option<f64> {
+    if let Ok(ts) = value.parse::<f64>() {
+        return Some(ts);
     }
+    let (num, unit) = match value.char_indices().last() {
+        Some((idx, _)) => value.split_at(idx),
+        None => return None,
+    };
+    let factor = match unit {
+        "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        "d" => 86400.0,
+        "w" => 604800.0,
+        _ => return None,
+    };
+    num.parse::<f64>().ok().map(|x| now - x * factor)
 }
 
-pub fn to_timestamp(tm: time::SystemTime) -> f64 {
-    let duration = tm.duration_since(time::UNIX_EPOCH).unwrap();
-    (duration.as_secs() as f64) + (duration.subsec_nanos() as f64 / 1e09)
+
+#[cfg(test)]
+mod tests {
+    use super::parse_http_date;
+
+    #[test]
+    fn test_parse_http_date() {
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(784111777.0));
+        assert_eq!(parse_http_date("Thu, 01 Jan 1970 00:00:00 GMT"), Some(0.0));
+        // leap days and the months after them
+        assert_eq!(parse_http_date("Thu, 29 Feb 2024 12:00:00 GMT"), Some(1709208000.0));
+        assert_eq!(parse_http_date("Wed, 01 Mar 2000 00:00:00 GMT"), Some(951868800.0));
+    }
+
+    #[test]
+    fn test_parse_http_date_rejects_other_formats() {
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 UTC"), None);
+        assert_eq!(parse_http_date("Sun, 06 Foo 1994 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:xx:37:00 GMT"), None);
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date(""), None);
+    }
 }